@@ -0,0 +1,168 @@
+//! LZ4 and Zstandard codecs. Each codec is behind a cargo feature; without
+//! it, data using the codec fails with [`BinaryError::UnsupportedCompression`].
+
+use std::borrow::Cow;
+
+use super::{BinaryError, Compression};
+
+/// Size of the chunks LZ4 compressed blocks are split into when writing.
+pub(crate) const LZ4_FRAME_SIZE: usize = 16384;
+
+/// LZ4 back-references reach at most this far into preceding data.
+pub(crate) const LZ4_WINDOW_SIZE: usize = 65536;
+
+/// LZ4 expands each compressed byte into at most this many bytes, so larger
+/// sizes in a header are rejected before the output is allocated.
+#[cfg(feature = "lz4")]
+const LZ4_MAX_RATIO: usize = 255;
+
+/// Largest Zstandard window accepted, the default limit of the reference
+/// decoder. The decoder allocates the whole window before decoding a frame.
+#[cfg(feature = "zstd")]
+const ZSTD_MAX_WINDOW_SIZE: u64 = 1 << 27;
+
+/// Decompresses a buffer and checks it has the size given in the header.
+pub(crate) fn decompress(
+    compression: Compression,
+    data: &[u8],
+    size: usize,
+) -> Result<Cow<'_, [u8]>, BinaryError> {
+    let output = match compression {
+        Compression::None => Cow::Borrowed(data),
+        Compression::Lz4 => Cow::Owned(lz4_decompress(data, size, &[])?),
+        Compression::Zstd => Cow::Owned(zstd_decompress(data, size)?),
+    };
+
+    if output.len() != size {
+        return Err(BinaryError::Malformed(
+            "decompressed buffer size does not match the header",
+        ));
+    }
+    Ok(output)
+}
+
+pub(crate) fn compress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, BinaryError> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Lz4 => lz4_compress(data),
+        Compression::Zstd => zstd_compress(data),
+    }
+}
+
+/// Decompresses a single LZ4 block of `size` bytes. `dict` holds the data
+/// decompressed before it, which back-references may point into.
+#[cfg(feature = "lz4")]
+pub(crate) fn lz4_decompress(
+    data: &[u8],
+    size: usize,
+    dict: &[u8],
+) -> Result<Vec<u8>, BinaryError> {
+    if size > data.len().saturating_mul(LZ4_MAX_RATIO).saturating_add(16) {
+        return Err(BinaryError::Decompression(format!(
+            "{} bytes of LZ4 data cannot hold {} bytes",
+            data.len(),
+            size
+        )));
+    }
+    lz4_flex::block::decompress_with_dict(data, size, dict)
+        .map_err(|err| BinaryError::Decompression(err.to_string()))
+}
+
+#[cfg(not(feature = "lz4"))]
+pub(crate) fn lz4_decompress(
+    _data: &[u8],
+    _size: usize,
+    _dict: &[u8],
+) -> Result<Vec<u8>, BinaryError> {
+    Err(BinaryError::UnsupportedCompression(Compression::Lz4))
+}
+
+#[cfg(feature = "lz4")]
+pub(crate) fn lz4_compress(data: &[u8]) -> Result<Vec<u8>, BinaryError> {
+    Ok(lz4_flex::block::compress(data))
+}
+
+#[cfg(not(feature = "lz4"))]
+pub(crate) fn lz4_compress(_data: &[u8]) -> Result<Vec<u8>, BinaryError> {
+    Err(BinaryError::UnsupportedCompression(Compression::Lz4))
+}
+
+/// Decompresses Zstandard frames into at most `size` bytes. The output grows
+/// as data is decoded rather than being allocated from `size` up front.
+#[cfg(feature = "zstd")]
+fn zstd_decompress(data: &[u8], size: usize) -> Result<Vec<u8>, BinaryError> {
+    use std::io::Read;
+
+    let error = |err: &dyn std::fmt::Display| BinaryError::Decompression(err.to_string());
+    let mut input = data;
+    let mut output = Vec::new();
+    while !input.is_empty() {
+        match zstd_window_size(input) {
+            Some(window) if window <= ZSTD_MAX_WINDOW_SIZE => {}
+            Some(window) => {
+                return Err(BinaryError::Decompression(format!(
+                    "Zstandard window of {} bytes is too large",
+                    window
+                )))
+            }
+            None => {
+                return Err(BinaryError::Decompression(
+                    "truncated Zstandard frame".into(),
+                ))
+            }
+        }
+
+        let limit = (size.saturating_add(1) - output.len()) as u64;
+        ruzstd::decoding::StreamingDecoder::new(&mut input)
+            .map_err(|err| error(&err))?
+            .take(limit)
+            .read_to_end(&mut output)
+            .map_err(|err| error(&err))?;
+        if output.len() > size {
+            return Err(BinaryError::Decompression(format!(
+                "Zstandard data is larger than {} bytes",
+                size
+            )));
+        }
+    }
+    Ok(output)
+}
+
+/// Reads the window size from the header of the Zstandard frame at the start
+/// of `frame`.
+#[cfg(feature = "zstd")]
+fn zstd_window_size(frame: &[u8]) -> Option<u64> {
+    let descriptor = *frame.get(4)?;
+    if descriptor & 0x20 == 0 {
+        // An exponent and an eighths mantissa
+        let window = *frame.get(5)?;
+        let base = 1u64 << (10 + (window >> 3));
+        return Some(base + base / 8 * u64::from(window & 7));
+    }
+
+    // Single segment frames use their content size as the window
+    let start = 5 + [0, 1, 2, 4][usize::from(descriptor & 3)];
+    let len = [1, 2, 4, 8][usize::from(descriptor >> 6)];
+    let mut bytes = [0u8; 8];
+    bytes[..len].copy_from_slice(frame.get(start..start + len)?);
+    let size = u64::from_le_bytes(bytes);
+    Some(if len == 2 { size + 256 } else { size })
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_data: &[u8], _size: usize) -> Result<Vec<u8>, BinaryError> {
+    Err(BinaryError::UnsupportedCompression(Compression::Zstd))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(data: &[u8]) -> Result<Vec<u8>, BinaryError> {
+    Ok(ruzstd::encoding::compress_to_vec(
+        data,
+        ruzstd::encoding::CompressionLevel::Fastest,
+    ))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_data: &[u8]) -> Result<Vec<u8>, BinaryError> {
+    Err(BinaryError::UnsupportedCompression(Compression::Zstd))
+}
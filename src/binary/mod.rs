@@ -0,0 +1,340 @@
+//! Binary KV3, as found in the DATA blocks of compiled resources
+//! (`.vpcf_c`, `.vmdl_c`, `.vmat_c`, ...).
+//!
+//! Binary KV3 decodes into the same [`KV3Value`] tree as the text parser, so
+//! it can be inspected, written back as text, or deserialized with serde.
+//! [`to_vec`] goes the other way and encodes a [`KV3Value`] tree as binary KV3.
+//!
+//! ```rust,no_run
+//! use kv3::binary::{self, Version};
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let data = std::fs::read("particles/explosion.vpcf_c.data")?;
+//!     if Version::detect(&data).is_some() {
+//!         let root = binary::from_slice(&data)?;
+//!         println!("{:#?}", root);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Two container layouts are supported:
+//!
+//! - `VKV\x03` (legacy): magic, encoding GUID, format GUID, then a payload
+//!   that is stored uncompressed or with Valve's block compression. After
+//!   decompression the payload holds the string table followed by the root
+//!   value, with types and data interleaved in a single stream.
+//! - `\x013VK` to `\x053VK` (versions 1 to 5): a header with section counts,
+//!   followed by one buffer (two for version 5) holding separate sections for
+//!   1, 2, 4 and 8 byte values, the string table and the type stream. Binary
+//!   blobs are either stored in the byte section or, when the header declares
+//!   blocks, in separate blocks after the buffer.
+//!
+//! The buffers and blocks of versions 1 to 5 may be compressed with LZ4 or
+//! Zstandard, as may the payload of the legacy layout with LZ4. Decoding and
+//! encoding these requires the `lz4` and `zstd` cargo features; without them
+//! such data fails with [`BinaryError::UnsupportedCompression`]. LZ4 blocks
+//! are split into chunks whose compressed sizes follow the trailer, while
+//! Zstandard compresses the blocks in the same frame as the buffer holding
+//! the values.
+
+mod compression;
+mod reader;
+mod writer;
+
+use std::fmt;
+
+use crate::{KV3Value, DEFAULT_MAX_DEPTH};
+
+pub(crate) const MAGIC_LEGACY: u32 = 0x03564B56; // "VKV\x03"
+pub(crate) const MAGIC_V1: u32 = 0x4B563301; // "\x013VK"
+pub(crate) const MAGIC_V2: u32 = 0x4B563302; // "\x023VK"
+pub(crate) const MAGIC_V3: u32 = 0x4B563303; // "\x033VK"
+pub(crate) const MAGIC_V4: u32 = 0x4B563304; // "\x043VK"
+pub(crate) const MAGIC_V5: u32 = 0x4B563305; // "\x053VK"
+
+/// Marks the end of the decompressed buffer in versions 1 to 5.
+pub(crate) const TRAILER: u32 = 0xFFEEDD00;
+/// Marks the end of the payload in the legacy layout.
+pub(crate) const TRAILER_LEGACY: u32 = 0xFFFFFFFF;
+
+/// Legacy encoding GUIDs, as stored on disk.
+pub(crate) const ENCODING_BINARY_UNCOMPRESSED: [u8; 16] = [
+    0x00, 0x05, 0x86, 0x1B, 0xD8, 0xF7, 0xC1, 0x40, 0xAD, 0x82, 0x75, 0xA4, 0x82, 0x67, 0xE7, 0x14,
+];
+pub(crate) const ENCODING_BINARY_BLOCK_COMPRESSED: [u8; 16] = [
+    0x46, 0x1A, 0x79, 0x95, 0xBC, 0x95, 0x6C, 0x4F, 0xA7, 0x0B, 0x05, 0xBC, 0xA1, 0xB7, 0xDF, 0xD2,
+];
+pub(crate) const ENCODING_BINARY_BLOCK_LZ4: [u8; 16] = [
+    0x8A, 0x34, 0x47, 0x68, 0xA1, 0x63, 0x5C, 0x4F, 0xA1, 0x97, 0x53, 0x80, 0x6F, 0xD9, 0xB1, 0x19,
+];
+
+/// The `generic` format GUID, as stored on disk.
+pub(crate) const FORMAT_GENERIC: [u8; 16] = [
+    0x7C, 0x16, 0x12, 0x74, 0xE9, 0x06, 0x98, 0x46, 0xAF, 0xF2, 0xE6, 0x3E, 0xB5, 0x90, 0x37, 0xE7,
+];
+
+/// Compression methods used by versions 1 to 5.
+pub(crate) const COMPRESSION_NONE: u32 = 0;
+pub(crate) const COMPRESSION_LZ4: u32 = 1;
+pub(crate) const COMPRESSION_ZSTD: u32 = 2;
+
+/// Set on a type byte when a flag byte follows it.
+pub(crate) const TYPE_FLAGGED: u8 = 0x80;
+
+pub(crate) const TYPE_NULL: u8 = 1;
+pub(crate) const TYPE_BOOLEAN: u8 = 2;
+pub(crate) const TYPE_INT64: u8 = 3;
+pub(crate) const TYPE_UINT64: u8 = 4;
+pub(crate) const TYPE_DOUBLE: u8 = 5;
+pub(crate) const TYPE_STRING: u8 = 6;
+pub(crate) const TYPE_BINARY_BLOB: u8 = 7;
+pub(crate) const TYPE_ARRAY: u8 = 8;
+pub(crate) const TYPE_OBJECT: u8 = 9;
+pub(crate) const TYPE_ARRAY_TYPED: u8 = 10;
+pub(crate) const TYPE_INT32: u8 = 11;
+pub(crate) const TYPE_UINT32: u8 = 12;
+pub(crate) const TYPE_BOOLEAN_TRUE: u8 = 13;
+pub(crate) const TYPE_BOOLEAN_FALSE: u8 = 14;
+pub(crate) const TYPE_INT64_ZERO: u8 = 15;
+pub(crate) const TYPE_INT64_ONE: u8 = 16;
+pub(crate) const TYPE_DOUBLE_ZERO: u8 = 17;
+pub(crate) const TYPE_DOUBLE_ONE: u8 = 18;
+pub(crate) const TYPE_FLOAT: u8 = 19;
+pub(crate) const TYPE_INT16: u8 = 20;
+pub(crate) const TYPE_UINT16: u8 = 21;
+// Types 22 and 23 have no known layout and are read as unknown types
+pub(crate) const TYPE_ARRAY_TYPED_BYTE_LENGTH: u8 = 24;
+
+/// The binary KV3 container version, detected from the magic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    /// `VKV\x03`
+    Legacy,
+    /// `\x013VK`
+    V1,
+    /// `\x023VK`
+    V2,
+    /// `\x033VK`
+    V3,
+    /// `\x043VK`
+    V4,
+    /// `\x053VK`
+    V5,
+}
+
+impl Version {
+    /// Detects the binary KV3 version from the first four bytes of `data`.
+    pub fn detect(data: &[u8]) -> Option<Version> {
+        let magic = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+        Version::from_magic(magic)
+    }
+
+    pub(crate) fn from_magic(magic: u32) -> Option<Version> {
+        match magic {
+            MAGIC_LEGACY => Some(Version::Legacy),
+            MAGIC_V1 => Some(Version::V1),
+            MAGIC_V2 => Some(Version::V2),
+            MAGIC_V3 => Some(Version::V3),
+            MAGIC_V4 => Some(Version::V4),
+            MAGIC_V5 => Some(Version::V5),
+            _ => None,
+        }
+    }
+}
+
+/// Compression of the binary KV3 buffers and blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block compression, requires the `lz4` feature.
+    Lz4,
+    /// Zstandard, requires the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn from_method(method: u32) -> Option<Compression> {
+        match method {
+            COMPRESSION_NONE => Some(Compression::None),
+            COMPRESSION_LZ4 => Some(Compression::Lz4),
+            COMPRESSION_ZSTD => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn method(self) -> u32 {
+        match self {
+            Compression::None => COMPRESSION_NONE,
+            Compression::Lz4 => COMPRESSION_LZ4,
+            Compression::Zstd => COMPRESSION_ZSTD,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Compression::None => "Uncompressed",
+            Compression::Lz4 => "LZ4",
+            Compression::Zstd => "Zstandard",
+        }
+    }
+
+    /// The cargo feature that enables the codec.
+    fn feature(self) -> &'static str {
+        match self {
+            Compression::None => "default",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BinaryError {
+    /// The data does not start with a known binary KV3 magic.
+    UnknownMagic(u32),
+    /// The legacy encoding GUID is not known.
+    UnknownEncoding(String),
+    /// The compression method is not known.
+    UnknownCompression(u32),
+    /// The data uses a compression whose cargo feature is disabled.
+    UnsupportedCompression(Compression),
+    /// The compressed data is corrupt.
+    Decompression(String),
+    /// The data ended before the value was complete.
+    UnexpectedEof,
+    /// A type byte with an unknown type.
+    UnknownType(u8),
+    /// A string or key refers to a string table entry that does not exist.
+    InvalidStringIndex(i32),
+    /// A string is not valid UTF-8.
+    InvalidUtf8,
+    /// The buffer does not end with the expected trailer.
+    InvalidTrailer(u32),
+    /// The data is malformed, e.g. a section is larger than the buffer.
+    Malformed(&'static str),
+    /// Arrays and objects nest deeper than [`ReadOptions::max_depth`].
+    DepthLimitExceeded(usize),
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryError::UnknownMagic(magic) => {
+                write!(f, "unknown binary KV3 magic {:#010x}", magic)
+            }
+            BinaryError::UnknownEncoding(guid) => {
+                write!(f, "unknown binary KV3 encoding {{{}}}", guid)
+            }
+            BinaryError::UnknownCompression(method) => {
+                write!(f, "unknown binary KV3 compression method {}", method)
+            }
+            BinaryError::UnsupportedCompression(compression) => write!(
+                f,
+                "{} compressed binary KV3 requires the `{}` feature of kv3",
+                compression.name(),
+                compression.feature()
+            ),
+            BinaryError::Decompression(reason) => {
+                write!(f, "failed to decompress binary KV3: {}", reason)
+            }
+            BinaryError::UnexpectedEof => write!(f, "unexpected end of binary KV3 data"),
+            BinaryError::UnknownType(ty) => write!(f, "unknown binary KV3 type {}", ty),
+            BinaryError::InvalidStringIndex(index) => {
+                write!(f, "invalid string table index {}", index)
+            }
+            BinaryError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            BinaryError::InvalidTrailer(trailer) => write!(f, "invalid trailer {:#010x}", trailer),
+            BinaryError::Malformed(reason) => write!(f, "malformed binary KV3: {}", reason),
+            BinaryError::DepthLimitExceeded(max_depth) => {
+                write!(f, "binary KV3 nests deeper than {} levels", max_depth)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BinaryError {}
+
+/// Decodes binary KV3 into a [`KV3Value`] tree.
+pub fn from_slice(data: &[u8]) -> Result<KV3Value, BinaryError> {
+    from_slice_with(data, &ReadOptions::default())
+}
+
+/// Decodes binary KV3 into a [`KV3Value`] tree using the given options.
+pub fn from_slice_with(data: &[u8], options: &ReadOptions) -> Result<KV3Value, BinaryError> {
+    reader::read(data, options)
+}
+
+/// Options for [`from_slice_with`].
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// How deeply arrays and objects may nest, counting an object or array
+    /// at the root as 1. Deeper data fails with
+    /// [`BinaryError::DepthLimitExceeded`] instead of overflowing the stack.
+    ///
+    /// `None` removes the limit, which is only safe for trusted data.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+        }
+    }
+}
+
+/// Options for [`to_vec_with`].
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// The container layout to write. Version 2 and later store binary blobs
+    /// in separate blocks.
+    pub version: Version,
+    /// The compression to apply. The legacy layout only supports LZ4.
+    pub compression: Compression,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            version: Version::V1,
+            compression: Compression::None,
+        }
+    }
+}
+
+/// Encodes a [`KV3Value`] tree as uncompressed version 1 binary KV3.
+///
+/// ```rust
+/// use kv3::{binary, KV3Value};
+///
+/// let data = binary::to_vec(&KV3Value::Int(5)).unwrap();
+/// assert!(matches!(binary::from_slice(&data), Ok(KV3Value::Int(5))));
+/// ```
+pub fn to_vec(value: &KV3Value) -> Result<Vec<u8>, BinaryError> {
+    to_vec_with(value, &WriteOptions::default())
+}
+
+/// Encodes a [`KV3Value`] tree as binary KV3 using the given options.
+pub fn to_vec_with(value: &KV3Value, options: &WriteOptions) -> Result<Vec<u8>, BinaryError> {
+    writer::write(value, options)
+}
+
+/// Formats a GUID stored in the on-disk (mixed-endian) layout as text.
+pub(crate) fn format_guid(bytes: &[u8; 16]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        bytes[8],
+        bytes[9],
+        bytes[10],
+        bytes[11],
+        bytes[12],
+        bytes[13],
+        bytes[14],
+        bytes[15]
+    )
+}
@@ -0,0 +1,645 @@
+use std::borrow::Cow;
+
+use log::{debug, info};
+
+use super::compression::{self, LZ4_WINDOW_SIZE};
+use super::*;
+use crate::{KV3Flags, KV3Map, KV3Object};
+
+/// Upper bound for preallocating arrays and objects from untrusted counts.
+const MAX_PREALLOCATE: usize = 1024;
+
+pub(crate) fn read(data: &[u8], options: &ReadOptions) -> Result<KV3Value, BinaryError> {
+    let mut cursor = Cursor::new(data);
+    let magic = cursor.read_u32()?;
+
+    match Version::from_magic(magic) {
+        Some(Version::Legacy) => read_legacy(&mut cursor, options),
+        Some(version) => read_versioned(version, &mut cursor, options),
+        None => Err(BinaryError::UnknownMagic(magic)),
+    }
+}
+
+fn read_legacy(cursor: &mut Cursor, options: &ReadOptions) -> Result<KV3Value, BinaryError> {
+    info!("Reading legacy binary KV3...");
+
+    let encoding: [u8; 16] = cursor.read_array()?;
+    let _format: [u8; 16] = cursor.read_array()?;
+
+    let payload = match encoding {
+        ENCODING_BINARY_UNCOMPRESSED => Cow::Borrowed(cursor.rest()),
+        ENCODING_BINARY_BLOCK_COMPRESSED => Cow::Owned(block_decompress(cursor.rest())?),
+        ENCODING_BINARY_BLOCK_LZ4 => {
+            let size = cursor.read_u32()? as usize;
+            compression::decompress(Compression::Lz4, cursor.rest(), size)?
+        }
+        _ => return Err(BinaryError::UnknownEncoding(format_guid(&encoding))),
+    };
+
+    let mut stream = Cursor::new(&payload);
+    let string_count = stream.read_u32()?;
+    let strings = read_strings(&mut stream, string_count)?;
+
+    let mut reader = ValueReader {
+        streams: Streams::Inline(stream),
+        strings,
+        blocks: None,
+        depth: 0,
+        max_depth: options.max_depth,
+    };
+    let root = reader.read_value()?;
+
+    let trailer = reader.streams.types().read_u32()?;
+    if trailer != TRAILER_LEGACY {
+        return Err(BinaryError::InvalidTrailer(trailer));
+    }
+
+    Ok(root)
+}
+
+/// Counts of the fixed-size sections at the start of a buffer.
+struct SectionCounts {
+    bytes: usize,
+    shorts: usize,
+    ints: usize,
+    doubles: usize,
+}
+
+/// Sizes of one (possibly compressed) buffer.
+struct BufferSizes {
+    uncompressed: usize,
+    compressed: usize,
+}
+
+fn read_versioned(
+    version: Version,
+    cursor: &mut Cursor,
+    options: &ReadOptions,
+) -> Result<KV3Value, BinaryError> {
+    info!("Reading binary KV3 {:?}...", version);
+
+    let _format: [u8; 16] = cursor.read_array()?;
+    let method = cursor.read_u32()?;
+    let compression =
+        Compression::from_method(method).ok_or(BinaryError::UnknownCompression(method))?;
+    let mut frame_size = 0;
+    if version >= Version::V2 {
+        let _dictionary_id = cursor.read_u16()?;
+        frame_size = cursor.read_u16()? as usize;
+    }
+
+    let mut counts = SectionCounts {
+        bytes: cursor.read_u32()? as usize,
+        shorts: 0,
+        ints: cursor.read_u32()? as usize,
+        doubles: cursor.read_u32()? as usize,
+    };
+    let mut strings_and_types_size = None;
+    if version >= Version::V2 {
+        strings_and_types_size = Some(cursor.read_u32()? as usize);
+        let _object_count = cursor.read_u16()?;
+        let _array_count = cursor.read_u16()?;
+    }
+
+    let buffer0 = BufferSizes {
+        uncompressed: cursor.read_u32()? as usize,
+        compressed: cursor.read_u32()? as usize,
+    };
+    let block_count = cursor.read_u32()? as usize;
+    let block_total_size = cursor.read_u32()? as usize;
+
+    if version >= Version::V4 {
+        counts.shorts = cursor.read_u32()? as usize;
+        let _reserved = cursor.read_u32()?;
+    }
+
+    let buffer1 = if version >= Version::V5 {
+        let sizes = BufferSizes {
+            uncompressed: cursor.read_u32()? as usize,
+            compressed: cursor.read_u32()? as usize,
+        };
+        let counts = SectionCounts {
+            bytes: cursor.read_u32()? as usize,
+            shorts: cursor.read_u32()? as usize,
+            ints: cursor.read_u32()? as usize,
+            doubles: cursor.read_u32()? as usize,
+        };
+        Some((sizes, counts))
+    } else {
+        None
+    };
+
+    debug!(
+        "Binary KV3 compression = {:?}, blocks = {}, block size = {}",
+        compression, block_count, block_total_size
+    );
+
+    // Zstandard compresses the blocks in the same frame as the buffer
+    // holding the values, right after it.
+    let zstd_blocks = match compression {
+        Compression::Zstd if block_count > 0 => block_total_size,
+        _ => 0,
+    };
+    let data0 = read_buffer(
+        cursor,
+        compression,
+        &buffer0,
+        if buffer1.is_some() { 0 } else { zstd_blocks },
+    )?;
+    let data1 = match &buffer1 {
+        Some((sizes, _)) => Some(read_buffer(cursor, compression, sizes, zstd_blocks)?),
+        None => None,
+    };
+
+    // The string table lives in the first buffer; values live in the second
+    // buffer for version 5 and in the same buffer otherwise.
+    let mut buffer = Cursor::new(&data0[..buffer0.uncompressed]);
+    let mut sections = read_sections(&mut buffer, &counts)?;
+    let string_count = sections.ints.read_u32()?;
+    let strings = read_strings(&mut buffer, string_count)?;
+
+    let (mut buffer, zstd_block_data) = match (&data1, &buffer1) {
+        (Some(data1), Some((sizes, counts))) => {
+            let (values, block_data) = data1.split_at(sizes.uncompressed);
+            let mut buffer = Cursor::new(values);
+            sections = read_sections(&mut buffer, counts)?;
+            (buffer, block_data)
+        }
+        _ => (buffer, &data0[buffer0.uncompressed..]),
+    };
+
+    let lz4_blocks = compression == Compression::Lz4 && block_count > 0;
+    let types_size = if lz4_blocks {
+        // The compressed chunk sizes follow the trailer, so the end of the
+        // types is found from the header instead.
+        let string_table_size: usize = strings.iter().map(|s| s.len() + 1).sum();
+        strings_and_types_size
+            .and_then(|size| size.checked_sub(string_table_size))
+            .ok_or(BinaryError::Malformed("invalid strings and types size"))?
+    } else {
+        let tail_size = block_count
+            .checked_mul(4)
+            .and_then(|size| size.checked_add(4))
+            .ok_or(BinaryError::Malformed("block count is too large"))?;
+        buffer
+            .remaining()
+            .checked_sub(tail_size)
+            .ok_or(BinaryError::UnexpectedEof)?
+    };
+    let types = Cursor::new(buffer.read_bytes(types_size)?);
+    let block_sizes_size = block_count
+        .checked_mul(4)
+        .ok_or(BinaryError::Malformed("block count is too large"))?;
+    let block_sizes = Cursor::new(buffer.read_bytes(block_sizes_size)?);
+
+    let trailer = buffer.read_u32()?;
+    if trailer != TRAILER {
+        return Err(BinaryError::InvalidTrailer(trailer));
+    }
+
+    let block_data = if block_count > 0 {
+        Some(match compression {
+            Compression::None => Cow::Borrowed(cursor.read_bytes(block_total_size)?),
+            Compression::Lz4 => Cow::Owned(lz4_decompress_blocks(
+                cursor.rest(),
+                Cursor::new(block_sizes.data),
+                &mut buffer,
+                frame_size,
+            )?),
+            Compression::Zstd => Cow::Borrowed(zstd_block_data),
+        })
+    } else {
+        None
+    };
+
+    let mut reader = ValueReader {
+        streams: Streams::Split {
+            types,
+            bytes: sections.bytes,
+            shorts: sections.shorts,
+            ints: sections.ints,
+            doubles: sections.doubles,
+        },
+        strings,
+        blocks: block_data.as_deref().map(|data| Blocks {
+            sizes: block_sizes,
+            data: Cursor::new(data),
+        }),
+        depth: 0,
+        max_depth: options.max_depth,
+    };
+
+    reader.read_value()
+}
+
+/// Reads and decompresses a buffer, along with `extra` bytes of block data
+/// compressed after it.
+fn read_buffer<'a>(
+    cursor: &mut Cursor<'a>,
+    compression: Compression,
+    sizes: &BufferSizes,
+    extra: usize,
+) -> Result<Cow<'a, [u8]>, BinaryError> {
+    let size = sizes
+        .uncompressed
+        .checked_add(extra)
+        .ok_or(BinaryError::Malformed("buffer is too large"))?;
+    compression::decompress(compression, cursor.read_bytes(sizes.compressed)?, size)
+}
+
+/// Decompresses LZ4 compressed blocks. Each block is split into chunks of
+/// `frame_size` bytes, which are compressed separately but may refer back
+/// into the chunks before them. The compressed chunk sizes are read from
+/// `chunk_sizes`.
+fn lz4_decompress_blocks(
+    data: &[u8],
+    mut block_sizes: Cursor,
+    chunk_sizes: &mut Cursor,
+    frame_size: usize,
+) -> Result<Vec<u8>, BinaryError> {
+    if frame_size == 0 {
+        return Err(BinaryError::Malformed("LZ4 blocks without a frame size"));
+    }
+
+    let mut data = Cursor::new(data);
+    let mut output = Vec::new();
+    while block_sizes.remaining() > 0 {
+        let mut remaining = block_sizes.read_u32()? as usize;
+        while remaining > 0 {
+            let compressed = data.read_bytes(chunk_sizes.read_u16()? as usize)?;
+            let size = remaining.min(frame_size);
+            let dict = &output[output.len().saturating_sub(LZ4_WINDOW_SIZE)..];
+            let chunk = compression::lz4_decompress(compressed, size, dict)?;
+            if chunk.len() != size {
+                return Err(BinaryError::Malformed(
+                    "decompressed chunk size does not match the header",
+                ));
+            }
+            output.extend(chunk);
+            remaining -= size;
+        }
+    }
+
+    Ok(output)
+}
+
+/// Decompresses Valve's block compression used by the legacy layout.
+///
+/// The payload starts with the decompressed size in the low 24 bits of a
+/// `u32`; if the top bit is set the rest is stored as-is. Otherwise it is a
+/// series of 16-entry groups, each preceded by a `u16` mask where a set bit
+/// marks a back-reference (`u16`: 12 bit offset, 4 bit length) and a clear
+/// bit marks a literal byte.
+fn block_decompress(data: &[u8]) -> Result<Vec<u8>, BinaryError> {
+    let mut cursor = Cursor::new(data);
+    let header: [u8; 4] = cursor.read_array()?;
+    let size = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+
+    if header[3] & 0x80 != 0 {
+        return Ok(cursor.rest().to_vec());
+    }
+
+    let mut output = Vec::with_capacity(size);
+    while output.len() < size {
+        let mask = cursor.read_u16()?;
+        for bit in 0..16 {
+            if mask & (1 << bit) != 0 {
+                let token = cursor.read_u16()? as usize;
+                let offset = (token >> 4) + 1;
+                let length = (token & 0xF) + 3;
+                let start = output
+                    .len()
+                    .checked_sub(offset)
+                    .ok_or(BinaryError::Malformed(
+                        "back-reference before start of data",
+                    ))?;
+                // Byte by byte, as the source and destination may overlap
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            } else {
+                output.push(cursor.read_u8()?);
+            }
+
+            if output.len() >= size {
+                break;
+            }
+        }
+    }
+    output.truncate(size);
+
+    Ok(output)
+}
+
+struct Sections<'a> {
+    bytes: Cursor<'a>,
+    shorts: Cursor<'a>,
+    ints: Cursor<'a>,
+    doubles: Cursor<'a>,
+}
+
+/// Splits the aligned 1, 2, 4 and 8 byte sections off the start of a buffer.
+/// Only sections with data are preceded by padding.
+fn read_sections<'a>(
+    buffer: &mut Cursor<'a>,
+    counts: &SectionCounts,
+) -> Result<Sections<'a>, BinaryError> {
+    let mut section = |size: usize, count: usize| {
+        let len = count
+            .checked_mul(size)
+            .ok_or(BinaryError::Malformed("section count is too large"))?;
+        if len > 0 {
+            buffer.align(size);
+        }
+        buffer.read_bytes(len).map(Cursor::new)
+    };
+
+    Ok(Sections {
+        bytes: section(1, counts.bytes)?,
+        shorts: section(2, counts.shorts)?,
+        ints: section(4, counts.ints)?,
+        doubles: section(8, counts.doubles)?,
+    })
+}
+
+fn read_strings<'a>(cursor: &mut Cursor<'a>, count: u32) -> Result<Vec<&'a str>, BinaryError> {
+    let mut strings = Vec::with_capacity((count as usize).min(MAX_PREALLOCATE));
+    for _ in 0..count {
+        strings.push(cursor.read_cstr()?);
+    }
+    debug!("Read {} strings", strings.len());
+    Ok(strings)
+}
+
+/// Where each kind of data is read from: a single interleaved stream for the
+/// legacy layout, or separate sections for versions 1 to 5.
+enum Streams<'a> {
+    Inline(Cursor<'a>),
+    Split {
+        types: Cursor<'a>,
+        bytes: Cursor<'a>,
+        shorts: Cursor<'a>,
+        ints: Cursor<'a>,
+        doubles: Cursor<'a>,
+    },
+}
+
+impl<'a> Streams<'a> {
+    fn types(&mut self) -> &mut Cursor<'a> {
+        match self {
+            Streams::Inline(cursor) => cursor,
+            Streams::Split { types, .. } => types,
+        }
+    }
+
+    fn bytes(&mut self) -> &mut Cursor<'a> {
+        match self {
+            Streams::Inline(cursor) => cursor,
+            Streams::Split { bytes, .. } => bytes,
+        }
+    }
+
+    fn shorts(&mut self) -> &mut Cursor<'a> {
+        match self {
+            Streams::Inline(cursor) => cursor,
+            Streams::Split { shorts, .. } => shorts,
+        }
+    }
+
+    fn ints(&mut self) -> &mut Cursor<'a> {
+        match self {
+            Streams::Inline(cursor) => cursor,
+            Streams::Split { ints, .. } => ints,
+        }
+    }
+
+    fn doubles(&mut self) -> &mut Cursor<'a> {
+        match self {
+            Streams::Inline(cursor) => cursor,
+            Streams::Split { doubles, .. } => doubles,
+        }
+    }
+}
+
+struct Blocks<'a> {
+    sizes: Cursor<'a>,
+    data: Cursor<'a>,
+}
+
+struct ValueReader<'a> {
+    streams: Streams<'a>,
+    strings: Vec<&'a str>,
+    blocks: Option<Blocks<'a>>,
+    /// Number of arrays and objects around the value being read.
+    depth: usize,
+    max_depth: Option<usize>,
+}
+
+impl<'a> ValueReader<'a> {
+    fn read_type(&mut self) -> Result<(u8, KV3Flags), BinaryError> {
+        let ty = self.streams.types().read_u8()?;
+        if ty & TYPE_FLAGGED != 0 {
+            let flags = self.streams.types().read_u8()?;
+            Ok((ty & !TYPE_FLAGGED, KV3Flags::from_bits_retain(flags)))
+        } else {
+            Ok((ty, KV3Flags::NONE))
+        }
+    }
+
+    fn read_value(&mut self) -> Result<KV3Value, BinaryError> {
+        let (ty, flags) = self.read_type()?;
+        self.read_flagged(ty, flags)
+    }
+
+    fn read_flagged(&mut self, ty: u8, flags: KV3Flags) -> Result<KV3Value, BinaryError> {
+        let value = self.read_typed(ty)?;
+        if flags.is_empty() {
+            Ok(value)
+        } else {
+            Ok(KV3Value::Flagged {
+                flags,
+                value: Box::new(value),
+            })
+        }
+    }
+
+    /// Runs `read` for the items of an array or object, one level deeper.
+    fn nested<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, BinaryError>,
+    ) -> Result<T, BinaryError> {
+        if let Some(max_depth) = self.max_depth {
+            if self.depth >= max_depth {
+                return Err(BinaryError::DepthLimitExceeded(max_depth));
+            }
+        }
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn read_string(&mut self) -> Result<String, BinaryError> {
+        let index = self.streams.ints().read_i32()?;
+        if index == -1 {
+            return Ok(String::new());
+        }
+        usize::try_from(index)
+            .ok()
+            .and_then(|i| self.strings.get(i))
+            .map(|s| s.to_string())
+            .ok_or(BinaryError::InvalidStringIndex(index))
+    }
+
+    fn read_typed(&mut self, ty: u8) -> Result<KV3Value, BinaryError> {
+        let value = match ty {
+            TYPE_NULL => KV3Value::Null,
+            TYPE_BOOLEAN => KV3Value::Bool(self.streams.bytes().read_u8()? != 0),
+            TYPE_BOOLEAN_TRUE => KV3Value::Bool(true),
+            TYPE_BOOLEAN_FALSE => KV3Value::Bool(false),
+            TYPE_INT64 => KV3Value::Int(self.streams.doubles().read_u64()? as i64),
+            TYPE_UINT64 => KV3Value::UInt(self.streams.doubles().read_u64()?),
+            TYPE_DOUBLE => KV3Value::Double(f64::from_bits(self.streams.doubles().read_u64()?)),
+            TYPE_INT64_ZERO => KV3Value::Int(0),
+            TYPE_INT64_ONE => KV3Value::Int(1),
+            TYPE_DOUBLE_ZERO => KV3Value::Double(0.0),
+            TYPE_DOUBLE_ONE => KV3Value::Double(1.0),
+            TYPE_INT32 => KV3Value::Int(self.streams.ints().read_i32()? as i64),
+            TYPE_UINT32 => KV3Value::UInt(self.streams.ints().read_u32()? as u64),
+            TYPE_FLOAT => KV3Value::Double(f32::from_bits(self.streams.ints().read_u32()?) as f64),
+            TYPE_INT16 => KV3Value::Int(self.streams.shorts().read_u16()? as i16 as i64),
+            TYPE_UINT16 => KV3Value::UInt(self.streams.shorts().read_u16()? as u64),
+            TYPE_STRING => KV3Value::String(self.read_string()?),
+            TYPE_BINARY_BLOB => KV3Value::HexArray(self.read_blob()?),
+            TYPE_ARRAY => {
+                let count = self.streams.ints().read_u32()? as usize;
+                let mut items = Vec::with_capacity(count.min(MAX_PREALLOCATE));
+                self.nested(|reader| {
+                    for _ in 0..count {
+                        items.push(reader.read_value()?);
+                    }
+                    Ok(())
+                })?;
+                KV3Value::Array(items)
+            }
+            TYPE_ARRAY_TYPED | TYPE_ARRAY_TYPED_BYTE_LENGTH => {
+                let count = if ty == TYPE_ARRAY_TYPED {
+                    self.streams.ints().read_u32()? as usize
+                } else {
+                    self.streams.bytes().read_u8()? as usize
+                };
+                let (item_ty, item_flags) = self.read_type()?;
+                let mut items = Vec::with_capacity(count.min(MAX_PREALLOCATE));
+                self.nested(|reader| {
+                    for _ in 0..count {
+                        items.push(reader.read_flagged(item_ty, item_flags)?);
+                    }
+                    Ok(())
+                })?;
+                KV3Value::Array(items)
+            }
+            TYPE_OBJECT => {
+                let count = self.streams.ints().read_u32()? as usize;
+                let mut fields = KV3Map::with_capacity(count.min(MAX_PREALLOCATE));
+                self.nested(|reader| {
+                    for _ in 0..count {
+                        let key = reader.read_string()?;
+                        let value = reader.read_value()?;
+                        fields.insert(key, value);
+                    }
+                    Ok(())
+                })?;
+                KV3Value::Object(KV3Object::new(fields))
+            }
+            _ => return Err(BinaryError::UnknownType(ty)),
+        };
+
+        Ok(value)
+    }
+
+    fn read_blob(&mut self) -> Result<Vec<u8>, BinaryError> {
+        match &mut self.blocks {
+            Some(blocks) => {
+                let size = blocks.sizes.read_u32()? as usize;
+                Ok(blocks.data.read_bytes(size)?.to_vec())
+            }
+            None => {
+                let size = self.streams.ints().read_u32()? as usize;
+                Ok(self.streams.bytes().read_bytes(size)?.to_vec())
+            }
+        }
+    }
+}
+
+/// A little-endian reader over a byte slice.
+pub(crate) struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    /// Skips padding so the position is a multiple of `alignment`.
+    fn align(&mut self, alignment: usize) {
+        let aligned = self.pos.next_multiple_of(alignment);
+        self.pos = aligned.min(self.data.len());
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], BinaryError> {
+        if len > self.remaining() {
+            return Err(BinaryError::UnexpectedEof);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], BinaryError> {
+        let bytes = self.read_bytes(N)?;
+        Ok(bytes.try_into().expect("slice has the requested length"))
+    }
+
+    fn read_u8(&mut self) -> Result<u8, BinaryError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, BinaryError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, BinaryError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_i32(&mut self) -> Result<i32, BinaryError> {
+        Ok(i32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, BinaryError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_cstr(&mut self) -> Result<&'a str, BinaryError> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(BinaryError::UnexpectedEof)?;
+        let s = std::str::from_utf8(&rest[..len]).map_err(|_| BinaryError::InvalidUtf8)?;
+        self.pos += len + 1;
+        Ok(s)
+    }
+}
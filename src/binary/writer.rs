@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+
+use log::info;
+
+use super::compression::{self, LZ4_FRAME_SIZE};
+use super::*;
+use crate::KV3Flags;
+
+pub(crate) fn write(value: &KV3Value, options: &WriteOptions) -> Result<Vec<u8>, BinaryError> {
+    info!("Writing binary KV3 {:?}...", options.version);
+
+    let mut writer = ValueWriter {
+        inline: options.version == Version::Legacy,
+        // Blobs go into separate blocks from version 2 on
+        use_blocks: options.version >= Version::V2,
+        ..ValueWriter::default()
+    };
+    writer.write_value(value, KV3Flags::NONE)?;
+
+    match options.version {
+        Version::Legacy => writer.finish_legacy(options.compression),
+        version => writer.finish_versioned(version, options.compression),
+    }
+}
+
+#[derive(Default)]
+struct ValueWriter {
+    /// Write types and data into a single stream (`types`), as the legacy
+    /// layout does.
+    inline: bool,
+    use_blocks: bool,
+    strings: Vec<String>,
+    string_ids: HashMap<String, i32>,
+    types: Vec<u8>,
+    bytes: Vec<u8>,
+    shorts: Vec<u8>,
+    ints: Vec<u8>,
+    doubles: Vec<u8>,
+    blocks: Vec<Vec<u8>>,
+    object_count: usize,
+    array_count: usize,
+}
+
+impl ValueWriter {
+    fn bytes(&mut self) -> &mut Vec<u8> {
+        if self.inline {
+            &mut self.types
+        } else {
+            &mut self.bytes
+        }
+    }
+
+    fn ints(&mut self) -> &mut Vec<u8> {
+        if self.inline {
+            &mut self.types
+        } else {
+            &mut self.ints
+        }
+    }
+
+    fn doubles(&mut self) -> &mut Vec<u8> {
+        if self.inline {
+            &mut self.types
+        } else {
+            &mut self.doubles
+        }
+    }
+
+    fn write_type(&mut self, ty: u8, flags: KV3Flags) {
+        if flags.is_empty() {
+            self.types.push(ty);
+        } else {
+            self.types.push(ty | TYPE_FLAGGED);
+            self.types.push(flags.bits());
+        }
+    }
+
+    fn write_count(&mut self, count: usize) -> Result<(), BinaryError> {
+        let count = u32::try_from(count).map_err(|_| BinaryError::Malformed("too many items"))?;
+        self.ints().extend(count.to_le_bytes());
+        Ok(())
+    }
+
+    fn write_string(&mut self, s: &str) -> Result<(), BinaryError> {
+        let id = if s.is_empty() {
+            -1
+        } else if let Some(id) = self.string_ids.get(s) {
+            *id
+        } else {
+            let id = i32::try_from(self.strings.len())
+                .map_err(|_| BinaryError::Malformed("too many strings"))?;
+            self.strings.push(s.to_string());
+            self.string_ids.insert(s.to_string(), id);
+            id
+        };
+        self.ints().extend(id.to_le_bytes());
+        Ok(())
+    }
+
+    fn write_value(&mut self, value: &KV3Value, flags: KV3Flags) -> Result<(), BinaryError> {
+        match value {
+            KV3Value::Flagged {
+                flags: inner,
+                value,
+            } => return self.write_value(value, flags | *inner),
+            KV3Value::Number { value, .. } => return self.write_value(value, flags),
+            KV3Value::Null => self.write_type(TYPE_NULL, flags),
+            KV3Value::Bool(true) => self.write_type(TYPE_BOOLEAN_TRUE, flags),
+            KV3Value::Bool(false) => self.write_type(TYPE_BOOLEAN_FALSE, flags),
+            KV3Value::Int(0) => self.write_type(TYPE_INT64_ZERO, flags),
+            KV3Value::Int(1) => self.write_type(TYPE_INT64_ONE, flags),
+            KV3Value::Int(i) => match i32::try_from(*i) {
+                Ok(small) => {
+                    self.write_type(TYPE_INT32, flags);
+                    self.ints().extend(small.to_le_bytes());
+                }
+                Err(_) => {
+                    self.write_type(TYPE_INT64, flags);
+                    self.doubles().extend(i.to_le_bytes());
+                }
+            },
+            KV3Value::UInt(u) => match u32::try_from(*u) {
+                Ok(small) => {
+                    self.write_type(TYPE_UINT32, flags);
+                    self.ints().extend(small.to_le_bytes());
+                }
+                Err(_) => {
+                    self.write_type(TYPE_UINT64, flags);
+                    self.doubles().extend(u.to_le_bytes());
+                }
+            },
+            KV3Value::Double(d) if d.to_bits() == 0.0f64.to_bits() => {
+                self.write_type(TYPE_DOUBLE_ZERO, flags)
+            }
+            KV3Value::Double(d) if *d == 1.0 => self.write_type(TYPE_DOUBLE_ONE, flags),
+            KV3Value::Double(d) => {
+                self.write_type(TYPE_DOUBLE, flags);
+                self.doubles().extend(d.to_le_bytes());
+            }
+            KV3Value::String(s) => {
+                self.write_type(TYPE_STRING, flags);
+                self.write_string(s)?;
+            }
+            KV3Value::HexArray(blob) => {
+                self.write_type(TYPE_BINARY_BLOB, flags);
+                if self.use_blocks {
+                    self.blocks.push(blob.clone());
+                } else {
+                    self.write_count(blob.len())?;
+                    self.bytes().extend_from_slice(blob);
+                }
+            }
+            KV3Value::Array(items) => {
+                self.array_count += 1;
+                self.write_type(TYPE_ARRAY, flags);
+                self.write_count(items.len())?;
+                for item in items {
+                    self.write_value(item, KV3Flags::NONE)?;
+                }
+            }
+            KV3Value::Object(obj) => {
+                self.object_count += 1;
+                self.write_type(TYPE_OBJECT, flags);
+                self.write_count(obj.len())?;
+                for (key, value) in obj.iter() {
+                    self.write_string(key)?;
+                    self.write_value(value, KV3Flags::NONE)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn string_table(&self) -> Vec<u8> {
+        let mut table = Vec::new();
+        for s in &self.strings {
+            table.extend_from_slice(s.as_bytes());
+            table.push(0);
+        }
+        table
+    }
+
+    fn finish_legacy(self, compression: Compression) -> Result<Vec<u8>, BinaryError> {
+        let mut payload = len_u32(self.strings.len())?.to_le_bytes().to_vec();
+        payload.extend(self.string_table());
+        payload.extend(&self.types);
+        payload.extend(TRAILER_LEGACY.to_le_bytes());
+
+        let mut out = MAGIC_LEGACY.to_le_bytes().to_vec();
+        match compression {
+            Compression::None => {
+                out.extend(ENCODING_BINARY_UNCOMPRESSED);
+                out.extend(FORMAT_GENERIC);
+                out.extend(payload);
+            }
+            Compression::Lz4 => {
+                out.extend(ENCODING_BINARY_BLOCK_LZ4);
+                out.extend(FORMAT_GENERIC);
+                out.extend(len_u32(payload.len())?.to_le_bytes());
+                out.extend(compression::lz4_compress(&payload)?);
+            }
+            Compression::Zstd => {
+                return Err(BinaryError::Malformed(
+                    "the legacy layout does not support Zstandard",
+                ))
+            }
+        }
+        Ok(out)
+    }
+
+    fn finish_versioned(
+        self,
+        version: Version,
+        compression: Compression,
+    ) -> Result<Vec<u8>, BinaryError> {
+        let strings = self.string_table();
+        let string_count = len_u32(self.strings.len())?.to_le_bytes();
+
+        // Types, block sizes and the trailer close the buffer holding the values
+        let mut tail = self.types.clone();
+        for block in &self.blocks {
+            tail.extend(len_u32(block.len())?.to_le_bytes());
+        }
+        tail.extend(TRAILER.to_le_bytes());
+
+        // LZ4 compresses blocks in chunks, whose sizes follow the trailer
+        let block_total_size = self.blocks.iter().map(Vec::len).sum();
+        let mut block_data = Vec::new();
+        for block in &self.blocks {
+            if compression == Compression::Lz4 {
+                for chunk in block.chunks(LZ4_FRAME_SIZE) {
+                    let compressed = compression::lz4_compress(chunk)?;
+                    let size = u16::try_from(compressed.len())
+                        .map_err(|_| BinaryError::Malformed("LZ4 chunk is too large"))?;
+                    tail.extend(size.to_le_bytes());
+                    block_data.extend(compressed);
+                }
+            } else {
+                block_data.extend(block);
+            }
+        }
+
+        let value_counts = [
+            self.bytes.len(),
+            self.shorts.len() / 2,
+            self.ints.len() / 4,
+            self.doubles.len() / 8,
+        ];
+
+        // Version 5 keeps the string table in its own buffer
+        let (mut buffer0, counts0, mut buffer1) = if version >= Version::V5 {
+            let mut buffer0 = write_sections(&[], &[], &string_count, &[]);
+            buffer0.extend(&strings);
+
+            let mut buffer1 = write_sections(&self.bytes, &self.shorts, &self.ints, &self.doubles);
+            buffer1.extend(&tail);
+
+            (buffer0, [0, 0, 1, 0], Some(buffer1))
+        } else {
+            let mut ints = string_count.to_vec();
+            ints.extend(&self.ints);
+
+            let mut buffer0 = write_sections(&self.bytes, &self.shorts, &ints, &self.doubles);
+            buffer0.extend(&strings);
+            buffer0.extend(&tail);
+
+            let [bytes, shorts, ints, doubles] = value_counts;
+            (buffer0, [bytes, shorts, ints + 1, doubles], None)
+        };
+
+        let [bytes, shorts, ints, doubles] = counts0;
+
+        // Zstandard compresses the blocks along with the buffer holding the values
+        let size0 = buffer0.len();
+        let size1 = buffer1.as_ref().map(Vec::len);
+        if compression == Compression::Zstd {
+            buffer1
+                .as_mut()
+                .unwrap_or(&mut buffer0)
+                .append(&mut block_data);
+        }
+        let buffer0 = compression::compress(compression, &buffer0)?;
+        let buffer1 = match buffer1 {
+            Some(buffer1) => Some(compression::compress(compression, &buffer1)?),
+            None => None,
+        };
+
+        let mut out = Vec::new();
+        let magic = match version {
+            Version::V1 => MAGIC_V1,
+            Version::V2 => MAGIC_V2,
+            Version::V3 => MAGIC_V3,
+            Version::V4 => MAGIC_V4,
+            _ => MAGIC_V5,
+        };
+        out.extend(magic.to_le_bytes());
+        out.extend(FORMAT_GENERIC);
+        out.extend(compression.method().to_le_bytes());
+        if version >= Version::V2 {
+            let frame_size = match compression {
+                Compression::Lz4 => LZ4_FRAME_SIZE as u16,
+                _ => 0,
+            };
+            out.extend(0u16.to_le_bytes()); // compression dictionary id
+            out.extend(frame_size.to_le_bytes());
+        }
+        out.extend(len_u32(bytes)?.to_le_bytes());
+        out.extend(len_u32(ints)?.to_le_bytes());
+        out.extend(len_u32(doubles)?.to_le_bytes());
+        if version >= Version::V2 {
+            out.extend(len_u32(strings.len() + self.types.len())?.to_le_bytes());
+            out.extend(len_u16(self.object_count).to_le_bytes());
+            out.extend(len_u16(self.array_count).to_le_bytes());
+        }
+        out.extend(len_u32(size0)?.to_le_bytes());
+        out.extend(len_u32(buffer0.len())?.to_le_bytes());
+        out.extend(len_u32(self.blocks.len())?.to_le_bytes());
+        out.extend(len_u32(block_total_size)?.to_le_bytes());
+        if version >= Version::V4 {
+            out.extend(len_u32(shorts)?.to_le_bytes());
+            out.extend(0u32.to_le_bytes()); // reserved
+        }
+        if let (Some(size), Some(buffer1)) = (size1, &buffer1) {
+            out.extend(len_u32(size)?.to_le_bytes());
+            out.extend(len_u32(buffer1.len())?.to_le_bytes());
+            for count in value_counts {
+                out.extend(len_u32(count)?.to_le_bytes());
+            }
+        }
+
+        out.extend(buffer0);
+        if let Some(buffer1) = buffer1 {
+            out.extend(buffer1);
+        }
+        out.extend(block_data);
+
+        Ok(out)
+    }
+}
+
+/// Writes the 1, 2, 4 and 8 byte sections, each aligned to its value size.
+/// Empty sections are not padded, so the string table of a version 5 buffer
+/// follows the string count directly.
+fn write_sections(bytes: &[u8], shorts: &[u8], ints: &[u8], doubles: &[u8]) -> Vec<u8> {
+    let mut buffer = bytes.to_vec();
+    for (alignment, section) in [(2, shorts), (4, ints), (8, doubles)] {
+        if section.is_empty() {
+            continue;
+        }
+        buffer.resize(buffer.len().next_multiple_of(alignment), 0);
+        buffer.extend_from_slice(section);
+    }
+    buffer
+}
+
+fn len_u32(len: usize) -> Result<u32, BinaryError> {
+    u32::try_from(len).map_err(|_| BinaryError::Malformed("section is too large"))
+}
+
+/// Object and array counts in the header are informational and saturate.
+fn len_u16(len: usize) -> u16 {
+    u16::try_from(len).unwrap_or(u16::MAX)
+}
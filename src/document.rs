@@ -0,0 +1,433 @@
+//! Editing KV3 text without losing comments or formatting.
+//!
+//! A [`Document`] keeps the text it was parsed from together with the
+//! location of every key and value in it. Reading a value parses the text at
+//! its location, and changing one replaces only that text, so comments, blank
+//! lines and the layout of everything else are written back untouched.
+//!
+//! Values are addressed by paths of keys separated by `.` and array indices
+//! in brackets, e.g. `m_Children[3].m_hModel`. Keys containing `.`, `[` or
+//! `]` are quoted in brackets, e.g. `m_Data["m_Key.x"]`.
+//!
+//! ```rust
+//! use kv3::{document::Document, KV3Value};
+//!
+//! let input = "{\n\t// Spawn limit\n\tm_nMaxParticles = 16\n}\n";
+//!
+//! let mut doc = Document::parse(input).unwrap();
+//! doc.set("m_nMaxParticles", &KV3Value::Int(32)).unwrap();
+//! assert_eq!(doc.to_string(), "{\n\t// Spawn limit\n\tm_nMaxParticles = 32\n}\n");
+//! ```
+
+use std::{fmt, ops::Range, str::FromStr};
+
+use nom::combinator::opt;
+
+use crate::path::{parse_path, Segment};
+use crate::writer::{entry_to_string, value_to_string};
+use crate::{
+    parse_comment, parse_header, parse_key, parse_kv3_with, parse_value,
+    skip_comments_and_whitespace, DuplicateKeys, Error, KV3Header, KV3Value, ParseOptions,
+};
+
+/// KV3 text that can be read and changed by path while keeping its comments
+/// and formatting.
+#[derive(Debug, Clone)]
+pub struct Document {
+    source: String,
+    options: ParseOptions,
+    header: Option<KV3Header>,
+    root: Node,
+}
+
+/// Location of a value in the source text.
+#[derive(Debug, Clone)]
+enum Node {
+    Object {
+        span: Range<usize>,
+        entries: Vec<Entry>,
+    },
+    Array {
+        span: Range<usize>,
+        items: Vec<Node>,
+    },
+    /// Any other value. Flagged arrays and objects are scalars too, so paths
+    /// do not lead into them.
+    Scalar { span: Range<usize> },
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    key: String,
+    /// From the start of the key to the end of the value.
+    span: Range<usize>,
+    value: Node,
+}
+
+impl Document {
+    /// Parses a KV3 document using the default [`ParseOptions`]. The header
+    /// is optional.
+    pub fn parse(input: &str) -> Result<Document, Error> {
+        Document::parse_with(input, &ParseOptions::default())
+    }
+
+    /// Parses a KV3 document using the given [`ParseOptions`], which are also
+    /// used to read values from it later on.
+    pub fn parse_with(input: &str, options: &ParseOptions) -> Result<Document, Error> {
+        // The regular parser checks the input and reports any errors, so
+        // indexing it afterwards cannot fail
+        let (header, _) = parse_kv3_with(input, options)?;
+        let root = Indexer {
+            source: input,
+            options,
+        }
+        .root()
+        .ok_or_else(|| Error::Message("failed to index a valid KV3 document".to_string()))?;
+
+        Ok(Document {
+            source: input.to_string(),
+            options: options.clone(),
+            header,
+            root,
+        })
+    }
+
+    /// The header of the document, if it has one.
+    pub fn header(&self) -> Option<&KV3Header> {
+        self.header.as_ref()
+    }
+
+    /// The text of the document, including all edits.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the value at `path`, or `None` if the path is malformed or
+    /// leads nowhere. The empty path is the root object.
+    pub fn get(&self, path: &str) -> Option<KV3Value> {
+        let text = self.get_text(path)?;
+        parse_value(text, &self.options, 0)
+            .ok()
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the text of the value at `path` as written in the document.
+    pub fn get_text(&self, path: &str) -> Option<&str> {
+        let segments = parse_path(path).ok()?;
+        let node = self.find(&segments)?;
+        Some(&self.source[node.span()])
+    }
+
+    /// Sets the value at `path`. An existing value is replaced in place; a
+    /// missing key is added on a new line after the last entry of its object
+    /// and any comment following it, indented like it. An object written on
+    /// a single line gets the entry on the same line, or is spread over
+    /// several lines if the entry does not fit on one.
+    ///
+    /// Fails if the path is malformed, its parent is not an object or
+    /// array, or it points past the end of an array.
+    pub fn set(&mut self, path: &str, value: &KV3Value) -> Result<(), Error> {
+        let segments = parse_path(path)?;
+        if let Some(node) = self.find(&segments) {
+            let span = node.span();
+            let text = value_to_string(value, &self.line_indent(span.start));
+            return self.replace(span, &text);
+        }
+
+        let (key, parent) = match segments.split_last() {
+            Some((Segment::Key(key), _)) if key.is_empty() => {
+                return Err(Error::path(path, "keys cannot be empty"))
+            }
+            Some((Segment::Key(key), parent)) => (key, parent),
+            _ => return Err(Error::path(path, "the array index is out of range")),
+        };
+        let (span, entries) = match self.find(parent) {
+            Some(Node::Object { span, entries }) => (span.clone(), entries),
+            _ => return Err(Error::path(path, "the parent is not an object")),
+        };
+
+        match entries.last() {
+            Some(last) if !self.source[span.clone()].contains('\n') => {
+                let entry = entry_to_string(key, value, "");
+                if !entry.contains('\n') {
+                    let at = last.span.end;
+                    return self.replace(at..at, &format!(" {}", entry));
+                }
+
+                // Give every entry, with the comments after it, its own line
+                let indent = self.line_indent(span.start);
+                let inner = format!("{}{}", indent, self.indent_unit());
+                let close = span.end - 1;
+                let mut text = String::from("{");
+                let mut from = span.start + 1;
+                for to in entries.iter().skip(1).map(|e| e.span.start).chain([close]) {
+                    let part = self.source[from..to].trim();
+                    if !part.is_empty() {
+                        text += &format!("\n{}{}", inner, part);
+                    }
+                    from = to;
+                }
+                text += &format!(
+                    "\n{}{}\n{}}}",
+                    inner,
+                    entry_to_string(key, value, &inner),
+                    indent
+                );
+                self.replace(span, &text)
+            }
+            Some(last) => {
+                let indent = self.line_indent(last.span.start);
+                let at = self.after_trailing_comment(last.span.end);
+                let text = format!("\n{}{}", indent, entry_to_string(key, value, &indent));
+                self.replace(at..at, &text)
+            }
+            None => {
+                // Put the entry on its own line, keeping anything written
+                // between the braces
+                let indent = self.line_indent(span.start);
+                let inner = format!("{}{}", indent, self.indent_unit());
+                let close = span.end - 1;
+                let content_end =
+                    span.start + 1 + self.source[span.start + 1..close].trim_end().len();
+                let text = format!(
+                    "\n{}{}\n{}",
+                    inner,
+                    entry_to_string(key, value, &inner),
+                    indent
+                );
+                self.replace(content_end..close, &text)
+            }
+        }
+    }
+
+    /// Removes the entry or array item at `path` and returns its value.
+    /// Lines left empty by the removal are removed too, as are the spaces
+    /// separating it from anything else on its line.
+    pub fn remove(&mut self, path: &str) -> Option<KV3Value> {
+        let segments = parse_path(path).ok()?;
+        let (last, parent) = segments.split_last()?;
+
+        let (span, value_span) = match (self.find(parent)?, last) {
+            (Node::Object { entries, .. }, Segment::Key(key)) => {
+                let entry = self.entry(entries, key)?;
+                (entry.span.clone(), entry.value.span())
+            }
+            (Node::Array { items, .. }, Segment::Index(index)) => {
+                let span = items.get(*index)?.span();
+                // The item's comma goes with it
+                let after = &self.source[span.end..];
+                let trimmed = after.trim_start();
+                let end = match trimmed.strip_prefix(',') {
+                    Some(_) => span.end + (after.len() - trimmed.len()) + 1,
+                    None => span.end,
+                };
+                (span.start..end, span)
+            }
+            _ => return None,
+        };
+
+        let (_, value) = parse_value(&self.source[value_span], &self.options, 0).ok()?;
+        let span = self.whole_lines(span);
+        self.replace(span, "").ok()?;
+        Some(value)
+    }
+
+    fn find(&self, segments: &[Segment]) -> Option<&Node> {
+        segments
+            .iter()
+            .try_fold(&self.root, |node, segment| match (node, segment) {
+                (Node::Object { entries, .. }, Segment::Key(key)) => {
+                    self.entry(entries, key).map(|entry| &entry.value)
+                }
+                (Node::Array { items, .. }, Segment::Index(index)) => items.get(*index),
+                _ => None,
+            })
+    }
+
+    /// Finds the entry for `key` that the parser would have kept.
+    fn entry<'e>(&self, entries: &'e [Entry], key: &str) -> Option<&'e Entry> {
+        let mut matches = entries.iter().filter(|entry| entry.key == key);
+        match self.options.duplicate_keys {
+            DuplicateKeys::FirstWins | DuplicateKeys::PreserveAll => matches.next(),
+            DuplicateKeys::Error | DuplicateKeys::LastWins => matches.next_back(),
+        }
+    }
+
+    /// Leading whitespace of the line containing `offset`.
+    fn line_indent(&self, offset: usize) -> String {
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        self.source[line_start..]
+            .chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .collect()
+    }
+
+    /// Indentation of the first indented line, or a tab if there is none.
+    fn indent_unit(&self) -> &str {
+        self.source
+            .lines()
+            .map(|line| &line[..line.len() - line.trim_start().len()])
+            .find(|indent| !indent.is_empty())
+            .unwrap_or("\t")
+    }
+
+    /// End of the comments starting on the line of `offset` if nothing else
+    /// follows them, otherwise `offset`. A block comment may run on over
+    /// several lines.
+    fn after_trailing_comment(&self, offset: usize) -> usize {
+        let mut end = offset;
+        loop {
+            let rest = self.source[end..].trim_start_matches(is_space);
+            match parse_comment(rest, &self.options.comments) {
+                Ok((after, _)) if after.len() < rest.len() => end = self.source.len() - after.len(),
+                _ => break,
+            }
+        }
+
+        let rest = self.source[end..].trim_start_matches(is_space);
+        if rest.is_empty() || rest.starts_with(['\r', '\n']) {
+            end
+        } else {
+            offset
+        }
+    }
+
+    /// Widens `span` to whole lines if nothing else is written on them, or
+    /// else over the spaces separating it from what follows on its line, or
+    /// from what precedes it if nothing follows.
+    fn whole_lines(&self, span: Range<usize>) -> Range<usize> {
+        let line_start = self.source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.source[span.end..]
+            .find('\n')
+            .map_or(self.source.len(), |i| span.end + i + 1);
+
+        if self.source[line_start..span.start].trim().is_empty()
+            && self.source[span.end..line_end].trim().is_empty()
+        {
+            line_start..line_end
+        } else if self.source[span.end..line_end].trim().is_empty() {
+            let before = self.source[..span.start].trim_end_matches(is_space);
+            before.len()..span.end
+        } else {
+            let after = self.source[span.end..].trim_start_matches(is_space);
+            span.start..self.source.len() - after.len()
+        }
+    }
+
+    /// Replaces the text in `span` and indexes the result. On error the
+    /// document is left unchanged.
+    fn replace(&mut self, span: Range<usize>, text: &str) -> Result<(), Error> {
+        let mut source = self.source.clone();
+        source.replace_range(span, text);
+        *self = Document::parse_with(&source, &self.options)?;
+        Ok(())
+    }
+}
+
+impl FromStr for Document {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Document::parse(input)
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Node {
+    fn span(&self) -> Range<usize> {
+        match self {
+            Node::Object { span, .. } | Node::Array { span, .. } | Node::Scalar { span } => {
+                span.clone()
+            }
+        }
+    }
+}
+
+/// Records where the keys and values of already validated text are.
+struct Indexer<'a, 'o> {
+    source: &'a str,
+    options: &'o ParseOptions,
+}
+
+impl<'a> Indexer<'a, '_> {
+    fn offset(&self, rest: &str) -> usize {
+        self.source.len() - rest.len()
+    }
+
+    fn skip(&self, input: &'a str) -> &'a str {
+        skip_comments_and_whitespace(input, self.options).map_or(input, |(rest, _)| rest)
+    }
+
+    fn root(&self) -> Option<Node> {
+        let (rest, _) = opt(parse_header)(self.source).ok()?;
+        self.value(self.skip(rest)).map(|(_, root)| root)
+    }
+
+    fn value(&self, input: &'a str) -> Option<(&'a str, Node)> {
+        if input.starts_with('{') {
+            return self.object(input);
+        }
+        if input.starts_with('[') {
+            return self.array(input);
+        }
+
+        let (rest, _) = parse_value(input, self.options, 0).ok()?;
+        // Flagged objects and arrays also consume the whitespace after them
+        let start = self.offset(input);
+        let end = start + input[..input.len() - rest.len()].trim_end().len();
+        Some((rest, Node::Scalar { span: start..end }))
+    }
+
+    fn object(&self, input: &'a str) -> Option<(&'a str, Node)> {
+        let start = self.offset(input);
+        let mut rest = self.skip(&input[1..]);
+        let mut entries = Vec::new();
+
+        loop {
+            if let Some(after) = rest.strip_prefix('}') {
+                let span = start..self.offset(after);
+                return Some((after, Node::Object { span, entries }));
+            }
+
+            let key_start = self.offset(rest);
+            let (after_key, key) = parse_key(rest, self.options).ok()?;
+            let after_equals = self.skip(self.skip(after_key).strip_prefix('=')?);
+            let (after_value, value) = self.value(after_equals)?;
+            entries.push(Entry {
+                key,
+                span: key_start..value.span().end,
+                value,
+            });
+            rest = self.skip(after_value);
+        }
+    }
+
+    fn array(&self, input: &'a str) -> Option<(&'a str, Node)> {
+        let start = self.offset(input);
+        let mut rest = self.skip(&input[1..]);
+        let mut items = Vec::new();
+
+        loop {
+            if let Some(after) = rest.strip_prefix(']') {
+                let span = start..self.offset(after);
+                return Some((after, Node::Array { span, items }));
+            }
+
+            let (after_item, item) = self.value(rest)?;
+            items.push(item);
+            rest = self.skip(after_item);
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = self.skip(after);
+            }
+        }
+    }
+}
+
+fn is_space(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
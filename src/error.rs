@@ -0,0 +1,194 @@
+use std::{borrow::Cow, fmt, io};
+
+use nom::error::ErrorKind;
+
+use crate::binary::BinaryError;
+
+/// Longest snippet of the offending line kept in [`Error::Syntax`].
+const SNIPPET_MAX_CHARS: usize = 60;
+
+/// Errors returned by the KV3 parsers, writers and serde functions.
+#[derive(Debug)]
+pub enum Error {
+    /// The input is not valid KV3 text.
+    Syntax {
+        /// 1-based line of the error.
+        line: usize,
+        /// 1-based column of the error, counted in characters.
+        column: usize,
+        /// The offending line, trimmed and shortened.
+        snippet: String,
+        /// What the parser expected at this position, e.g.
+        /// ``"`=` after key `m_nFlags`"``.
+        expected: String,
+    },
+    /// Binary KV3 could not be read or written.
+    Binary(BinaryError),
+    /// A path is malformed or does not lead to a value that can be changed.
+    Path {
+        /// The path as given.
+        path: String,
+        /// What is wrong with it.
+        reason: String,
+    },
+    /// A value could not be serialized or deserialized.
+    Message(String),
+    /// Writing the output failed.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Builds a [`Error::Syntax`] for an error at `at`, which must be a
+    /// suffix of `input`.
+    pub(crate) fn syntax(input: &str, at: &str, expected: impl Into<String>) -> Error {
+        let offset = input.len() - at.len();
+        let before = &input[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[offset..]
+            .find('\n')
+            .map_or(input.len(), |i| offset + i);
+
+        let line = before.matches('\n').count() + 1;
+        let column = before[line_start..].chars().count() + 1;
+
+        let text = input[line_start..line_end].trim();
+        let snippet = match text.char_indices().nth(SNIPPET_MAX_CHARS) {
+            Some((end, _)) => format!("{}...", &text[..end]),
+            None => text.to_string(),
+        };
+
+        Error::Syntax {
+            line,
+            column,
+            snippet,
+            expected: expected.into(),
+        }
+    }
+
+    /// Builds a [`Error::Path`] for `path`.
+    pub(crate) fn path(path: &str, reason: impl Into<String>) -> Error {
+        Error::Path {
+            path: path.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Syntax {
+                line,
+                column,
+                snippet,
+                expected,
+            } => write!(
+                f,
+                "expected {} at line {}, column {}: `{}`",
+                expected, line, column, snippet
+            ),
+            Error::Binary(err) => err.fmt(f),
+            Error::Path { path, reason } => write!(f, "invalid path `{}`: {}", path, reason),
+            Error::Message(msg) => f.write_str(msg),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Binary(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<BinaryError> for Error {
+    fn from(err: BinaryError) -> Self {
+        Error::Binary(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+/// Error of the internal nom parsers: where parsing stopped and what was
+/// expected there.
+#[derive(Debug)]
+pub(crate) struct ParseError<'a> {
+    pub(crate) input: &'a str,
+    pub(crate) expected: Cow<'static, str>,
+}
+
+pub(crate) type ParseResult<'a, O> = nom::IResult<&'a str, O, ParseError<'a>>;
+
+impl<'a> ParseError<'a> {
+    pub(crate) fn new(input: &'a str, expected: impl Into<Cow<'static, str>>) -> Self {
+        ParseError {
+            input,
+            expected: expected.into(),
+        }
+    }
+
+    /// Converts the error into an [`Error::Syntax`] located in `input`.
+    pub(crate) fn into_error(self, input: &str) -> Error {
+        Error::syntax(input, self.input, self.expected)
+    }
+}
+
+impl<'a> nom::error::ParseError<&'a str> for ParseError<'a> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        let expected = match kind {
+            ErrorKind::Float => "a number",
+            ErrorKind::Digit => "a digit",
+            ErrorKind::MapOpt => "a known flag",
+            ErrorKind::TakeUntil => "a closing delimiter",
+            ErrorKind::Eof => "the end of the input",
+            _ => "a value",
+        };
+        ParseError::new(input, expected)
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    /// Keeps the error that got furthest into the input.
+    fn or(self, other: Self) -> Self {
+        if other.input.len() < self.input.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// Converts the result of an internal parser into the public result type.
+pub(crate) fn finish<'a, O>(
+    input: &'a str,
+    result: ParseResult<'a, O>,
+) -> Result<(&'a str, O), Error> {
+    result.map_err(|err| match err {
+        nom::Err::Error(err) | nom::Err::Failure(err) => err.into_error(input),
+        nom::Err::Incomplete(_) => Error::syntax(input, "", "more input"),
+    })
+}
@@ -1,14 +1,14 @@
-use serde::Deserialize;
 use serde::{
     de::{self, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, Visitor},
-    forward_to_deserialize_any,
+    forward_to_deserialize_any, ser, Deserialize, Serialize,
 };
-use std::{collections::HashMap, fmt};
+use std::{fmt, io};
 
-use crate::{parse_kv3, KV3Object, KV3Value};
+use crate::error::finish;
+use crate::{binary, parse_document, write_kv3, Error, KV3Map, KV3Object, KV3Value, ParseOptions};
 
 impl<'de> Deserializer<'de> for KV3Object {
-    type Error = de::value::Error;
+    type Error = Error;
 
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
     where
@@ -28,12 +28,12 @@ impl<'de> Deserializer<'de> for KV3Object {
 }
 
 struct KV3ObjectMapAccess {
-    iter: std::collections::hash_map::IntoIter<String, KV3Value>,
+    iter: <KV3Map as IntoIterator>::IntoIter,
     value: Option<KV3Value>,
 }
 
 impl<'de> MapAccess<'de> for KV3ObjectMapAccess {
-    type Error = de::value::Error;
+    type Error = Error;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
     where
@@ -42,7 +42,7 @@ impl<'de> MapAccess<'de> for KV3ObjectMapAccess {
         if let Some((key, value)) = self.iter.next() {
             self.value = Some(value);
             // Wrap the result in Ok(Some(...))
-            Ok(Some(seed.deserialize(key.into_deserializer())?))
+            seed.deserialize(key.into_deserializer()).map(Some)
         } else {
             // Return Ok(None) when there are no more keys
             Ok(None)
@@ -65,7 +65,7 @@ struct KV3ValueSeqAccess {
 }
 
 impl<'de> serde::de::SeqAccess<'de> for KV3ValueSeqAccess {
-    type Error = de::value::Error;
+    type Error = Error;
 
     fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
     where
@@ -80,7 +80,7 @@ impl<'de> serde::de::SeqAccess<'de> for KV3ValueSeqAccess {
 }
 
 impl<'de> serde::Deserializer<'de> for KV3Value {
-    type Error = de::value::Error;
+    type Error = Error;
 
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
     where
@@ -89,6 +89,7 @@ impl<'de> serde::Deserializer<'de> for KV3Value {
         match self {
             KV3Value::Bool(b) => visitor.visit_bool(b),
             KV3Value::Int(i) => visitor.visit_i64(i),
+            KV3Value::UInt(u) => visitor.visit_u64(u),
             KV3Value::Double(d) => visitor.visit_f64(d),
             KV3Value::String(s) => visitor.visit_string(s),
             KV3Value::Array(arr) => {
@@ -111,23 +112,127 @@ impl<'de> serde::Deserializer<'de> for KV3Value {
                 value: None,
             }),
             KV3Value::Null => visitor.visit_unit(),
+            // Flags only describe the value, deserialize it as the inner value
+            KV3Value::Flagged { value, .. } => value.deserialize_any(visitor),
+            KV3Value::Number { value, .. } => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            KV3Value::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            KV3Value::Flagged { value, .. } => value.deserialize_enum(name, variants, visitor),
+            // Unit variants are written as their name
+            KV3Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            // Other variants are written as `{ Variant = value }`
+            KV3Value::Object(obj) if obj.fields.len() == 1 => {
+                let (variant, value) = obj
+                    .fields
+                    .into_iter()
+                    .next()
+                    .expect("object has exactly one field");
+                visitor.visit_enum(KV3EnumAccess { variant, value })
+            }
+            _ => Err(de::Error::custom(
+                "expected a string or an object with a single key for an enum",
+            )),
         }
     }
 
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
-        bytes byte_buf option unit unit_struct newtype_struct seq
-        tuple tuple_struct map struct enum identifier ignored_any
+        bytes byte_buf unit unit_struct newtype_struct seq
+        tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct KV3EnumAccess {
+    variant: String,
+    value: KV3Value,
+}
+
+impl<'de> de::EnumAccess<'de> for KV3EnumAccess {
+    type Error = Error;
+    type Variant = KV3VariantAccess;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let variant =
+            seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.variant))?;
+        Ok((variant, KV3VariantAccess { value: self.value }))
+    }
+}
+
+struct KV3VariantAccess {
+    value: KV3Value,
+}
+
+impl<'de> de::VariantAccess<'de> for KV3VariantAccess {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        match self.value {
+            KV3Value::Null => Ok(()),
+            _ => Err(de::Error::custom("expected a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        seed.deserialize(self.value)
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.value.deserialize_any(visitor)
+    }
+
+    fn struct_variant<V>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.value.deserialize_any(visitor)
     }
 }
 
 /// Parses your KV3 input data into a Rust structure.
 ///
+/// The `<!-- kv3 ... -->` header is optional. The whole input must be
+/// consumed: anything but whitespace and comments after the root object is
+/// an error.
+///
 /// # Example
 ///
 /// ```rust
 /// use serde::Deserialize;
-/// use kv3::kv3_serde::serde_kv3;
+/// use kv3::kv3_serde::from_str;
 ///
 /// #[derive(Debug, Deserialize)]
 /// struct MyStruct {
@@ -136,7 +241,7 @@ impl<'de> serde::Deserializer<'de> for KV3Value {
 ///     active: bool,
 /// }
 ///
-/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// fn main() -> Result<(), kv3::Error> {
 ///     let kv3_input = r#"
 ///     {
 ///         name = "Example"
@@ -145,8 +250,10 @@ impl<'de> serde::Deserializer<'de> for KV3Value {
 ///     }
 ///     "#;
 ///
-///     let my_struct: MyStruct = serde_kv3(kv3_input)?;
+///     let my_struct: MyStruct = from_str(kv3_input)?;
 ///     println!("{:?}", my_struct);
+///
+///     assert!(from_str::<MyStruct>("{ name = \"a\" value = 1 active = true } }").is_err());
 ///     Ok(())
 /// }
 /// ```
@@ -157,20 +264,33 @@ impl<'de> serde::Deserializer<'de> for KV3Value {
 /// MyStruct { name: "Example", value: 42, active: true }
 /// ```
 ///
-pub fn serde_kv3<'de, T>(input: &'de str) -> Result<T, Box<dyn std::error::Error + 'de>>
+pub fn from_str<'de, T>(input: &'de str) -> Result<T, Error>
 where
     T: Deserialize<'de>,
 {
-    // Parse the KV3 data
-    let (_, parsed_kv3) = parse_kv3(input)?;
-
-    // Wrap the parsed KV3 data in KV3Object
-    let kv3_object = KV3Object { fields: parsed_kv3 };
+    // Parse the KV3 data, rejecting anything after the root object
+    let (_, (_, kv3_object)) = finish(input, parse_document(input, &ParseOptions::default()))?;
 
     // Deserialize directly into the target struct
-    let result: T = T::deserialize(kv3_object)?;
+    T::deserialize(kv3_object)
+}
 
-    Ok(result)
+/// Parses your KV3 input data into a Rust structure, see [`from_str`].
+pub fn serde_kv3<'de, T>(input: &'de str) -> Result<T, Error>
+where
+    T: Deserialize<'de>,
+{
+    from_str(input)
+}
+
+/// Parses binary KV3, e.g. the DATA block of a compiled resource, into a
+/// Rust structure.
+pub fn serde_kv3_binary<T>(input: &[u8]) -> Result<T, Error>
+where
+    T: de::DeserializeOwned,
+{
+    let value = binary::from_slice(input)?;
+    T::deserialize(value)
 }
 
 impl<'de> Deserialize<'de> for KV3Value {
@@ -201,6 +321,14 @@ impl<'de> Deserialize<'de> for KV3Value {
                 Ok(KV3Value::Int(value))
             }
 
+            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                // Like parsed text, only values above i64::MAX are unsigned
+                Ok(i64::try_from(value).map_or(KV3Value::UInt(value), KV3Value::Int))
+            }
+
             fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
             where
                 E: de::Error,
@@ -215,6 +343,48 @@ impl<'de> Deserialize<'de> for KV3Value {
                 Ok(KV3Value::String(value.to_string()))
             }
 
+            fn visit_string<E>(self, value: String) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(KV3Value::String(value))
+            }
+
+            fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(KV3Value::HexArray(value.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(KV3Value::HexArray(value))
+            }
+
+            fn visit_unit<E>(self) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(KV3Value::Null)
+            }
+
+            fn visit_none<E>(self) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(KV3Value::Null)
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                Deserialize::deserialize(deserializer)
+            }
+
             fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
             where
                 A: de::SeqAccess<'de>,
@@ -230,14 +400,461 @@ impl<'de> Deserialize<'de> for KV3Value {
             where
                 M: de::MapAccess<'de>,
             {
-                let mut fields = HashMap::new();
+                let mut fields = KV3Map::new();
                 while let Some((key, value)) = map.next_entry()? {
                     fields.insert(key, value);
                 }
-                Ok(KV3Value::Object(KV3Object { fields }))
+                Ok(KV3Value::Object(KV3Object::new(fields)))
             }
         }
 
         deserializer.deserialize_any(KV3ValueVisitor)
     }
 }
+
+impl<'de> Deserialize<'de> for KV3Object {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        match KV3Value::deserialize(deserializer)? {
+            KV3Value::Object(obj) => Ok(obj),
+            _ => Err(de::Error::custom("expected a KV3 object")),
+        }
+    }
+}
+
+/// Flagged values serialize as their inner value, dropping the flags; write
+/// them with [`to_kv3_string`](crate::to_kv3_string) or
+/// [`binary::to_vec`](crate::binary::to_vec) to keep them.
+impl Serialize for KV3Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            KV3Value::Bool(b) => serializer.serialize_bool(*b),
+            KV3Value::Int(i) => serializer.serialize_i64(*i),
+            KV3Value::UInt(u) => serializer.serialize_u64(*u),
+            KV3Value::Double(d) => serializer.serialize_f64(*d),
+            KV3Value::String(s) => serializer.serialize_str(s),
+            KV3Value::Array(arr) => arr.serialize(serializer),
+            KV3Value::HexArray(arr) => serializer.serialize_bytes(arr),
+            KV3Value::Object(obj) => obj.serialize(serializer),
+            KV3Value::Null => serializer.serialize_unit(),
+            KV3Value::Flagged { value, .. } => value.serialize(serializer),
+            KV3Value::Number { value, .. } => value.serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for KV3Object {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(self.iter())
+    }
+}
+
+/// Converts any `T: Serialize` into a [`KV3Value`] tree.
+pub fn to_value<T>(value: &T) -> Result<KV3Value, Error>
+where
+    T: Serialize + ?Sized,
+{
+    value.serialize(Serializer)
+}
+
+/// Serializes your Rust structure into KV3 text, including the default header.
+///
+/// # Example
+///
+/// ```rust
+/// use serde::Serialize;
+/// use kv3::kv3_serde::to_string;
+///
+/// #[derive(Serialize)]
+/// struct MyStruct {
+///     name: String,
+///     value: i32,
+///     active: bool,
+/// }
+///
+/// let my_struct = MyStruct {
+///     name: "Example".to_string(),
+///     value: 42,
+///     active: true,
+/// };
+///
+/// let kv3 = to_string(&my_struct).unwrap();
+/// assert!(kv3.contains("value = 42"));
+/// ```
+pub fn to_string<T>(value: &T) -> Result<String, Error>
+where
+    T: Serialize + ?Sized,
+{
+    let mut buffer = Vec::new();
+    to_writer(&mut buffer, value)?;
+    String::from_utf8(buffer).map_err(ser::Error::custom)
+}
+
+/// Serializes your Rust structure as KV3 text into the given writer.
+pub fn to_writer<W, T>(writer: W, value: &T) -> Result<(), Error>
+where
+    W: io::Write,
+    T: Serialize + ?Sized,
+{
+    let value = to_value(value)?;
+    Ok(write_kv3(writer, &value)?)
+}
+
+/// A serde [`Serializer`](serde::Serializer) that builds a [`KV3Value`] tree,
+/// which can then be written with [`write_kv3`](crate::write_kv3).
+pub struct Serializer;
+
+impl serde::Serializer for Serializer {
+    type Ok = KV3Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeTupleVariant;
+    type SerializeMap = SerializeObject;
+    type SerializeStruct = SerializeObject;
+    type SerializeStructVariant = SerializeStructVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<KV3Value, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<KV3Value, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<KV3Value, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::Int(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<KV3Value, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<KV3Value, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<KV3Value, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<KV3Value, Self::Error> {
+        // Like parsed text, only values above i64::MAX are unsigned
+        Ok(i64::try_from(v).map_or(KV3Value::UInt(v), KV3Value::Int))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<KV3Value, Self::Error> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::Double(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::HexArray(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::Null)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<KV3Value, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<KV3Value, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<KV3Value, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let mut fields = KV3Map::new();
+        fields.insert(variant.to_string(), value.serialize(self)?);
+        Ok(KV3Value::Object(KV3Object::new(fields)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(SerializeArray {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Ok(SerializeTupleVariant {
+            variant,
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(SerializeObject {
+            fields: KV3Map::new(),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Ok(SerializeStructVariant {
+            variant,
+            fields: KV3Map::new(),
+        })
+    }
+}
+
+pub struct SerializeArray {
+    items: Vec<KV3Value>,
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = KV3Value;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.items.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::Array(self.items))
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = KV3Value;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<KV3Value, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = KV3Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<KV3Value, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeTupleVariant {
+    variant: &'static str,
+    items: Vec<KV3Value>,
+}
+
+impl ser::SerializeTupleVariant for SerializeTupleVariant {
+    type Ok = KV3Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.items.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<KV3Value, Self::Error> {
+        let mut fields = KV3Map::new();
+        fields.insert(self.variant.to_string(), KV3Value::Array(self.items));
+        Ok(KV3Value::Object(KV3Object::new(fields)))
+    }
+}
+
+pub struct SerializeObject {
+    fields: KV3Map,
+    next_key: Option<String>,
+}
+
+impl ser::SerializeMap for SerializeObject {
+    type Ok = KV3Value;
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        // KV3 keys are identifiers, so only accept keys that are strings or integers
+        let key = match key.serialize(Serializer)? {
+            KV3Value::String(s) => s,
+            KV3Value::Int(i) => i.to_string(),
+            KV3Value::UInt(u) => u.to_string(),
+            _ => return Err(ser::Error::custom("KV3 object keys must be strings")),
+        };
+        self.next_key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| Error::Message("serialize_value called before serialize_key".into()))?;
+        self.fields.insert(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<KV3Value, Self::Error> {
+        Ok(KV3Value::Object(KV3Object::new(self.fields)))
+    }
+}
+
+impl ser::SerializeStruct for SerializeObject {
+    type Ok = KV3Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.fields
+            .insert(key.to_string(), value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<KV3Value, Self::Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+pub struct SerializeStructVariant {
+    variant: &'static str,
+    fields: KV3Map,
+}
+
+impl ser::SerializeStructVariant for SerializeStructVariant {
+    type Ok = KV3Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.fields
+            .insert(key.to_string(), value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<KV3Value, Self::Error> {
+        let mut fields = KV3Map::new();
+        fields.insert(
+            self.variant.to_string(),
+            KV3Value::Object(KV3Object::new(self.fields)),
+        );
+        Ok(KV3Value::Object(KV3Object::new(fields)))
+    }
+}
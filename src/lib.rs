@@ -7,10 +7,25 @@
 //! ## Features
 //!
 //! - **Parsing**: Convert KV3-formatted strings into Rust data structures.
+//! - **Key Order**: Object keys keep their order from the input (`preserve_order` feature, enabled by default).
+//! - **Accessors**: [`KV3Object::get`], `insert`, `remove`, `iter` and friends, typed [`KV3Value`] accessors such as [`KV3Value::as_i64`] and [`KV3Value::as_str`], and indexing like `root["m_Children"][0]["m_hModel"]`.
+//! - **Paths**: [`KV3Value::get_path`] with paths like `m_Children[3].m_hModel`, [`KV3Value::get_path_mut`], [`KV3Value::set_path`] and JSON Pointers with [`KV3Value::pointer`].
+//! - **Queries**: [`select`] finds values anywhere in a tree with a JSONPath-like query such as `$..m_hMaterial`, including wildcards, slices and filters such as `[?@.m_flRadius > 10]`, together with their paths.
+//! - **Building Values**: [`kv3!`] builds a [`KV3Value`] in code, e.g. `kv3!({ m_nFlags: 5, m_Names: ["a", "b"], blob: #[0xFF, 0x00] })`. [`KV3Value`] implements `Clone`, `PartialEq`, `Default` and `From` for primitives, strings, `Vec<T>`, `Vec<u8>` (hex arrays) and `HashMap`.
+//! - **Serialization**: Write [`KV3Value`] trees back to KV3 text with [`to_kv3_string`] and [`write_kv3`].
+//! - **Error Reporting**: Every entry point returns [`Error`], which reports the line, column and what was expected for invalid text.
 //! - **Support for Comments**: Handles single-line (`//`), multi-line (`/* ... */`), and XML-style (`<!-- ... -->`) comments.
 //! - **Support for Multiline Strings**: Parses multiline strings enclosed in triple double-quotes (`"""`).
-//! - **Handles Various Data Types**: Supports booleans, integers, floats, strings, arrays, hex arrays, objects, and null values.
-//! - **Customizable Parsing**: Built using the [`nom`](https://github.com/Geal/nom) parser combinator library for flexibility.
+//! - **String Escapes**: Decodes and writes `\"`, `\\`, `\n`, `\t` and `\r` in single-line strings.
+//! - **Keys**: Accepts quoted keys (`"my key" = 1`) and keys containing `.` or `:`, such as `m_Data.m_nCount`.
+//! - **Duplicate Keys**: [`ParseOptions::duplicate_keys`] rejects keys repeated within an object, keeps the first or last value, or keeps all of them for a faithful round trip.
+//! - **Handles Various Data Types**: Supports booleans, integers (including unsigned 64-bit values such as hashes and hex literals like `0x1F`), floats (including `inf`, `-inf` and `nan`), strings, arrays, hex arrays, objects, and null values.
+//! - **Binary KV3**: Decodes binary KV3 from compiled resources with [`binary::from_slice`]. LZ4 and Zstandard compression need the `lz4` and `zstd` features.
+//! - **Flagged Values**: Keeps value flags such as `resource:"models/foo.vmdl"` or `soundevent:"weapon.fire"`.
+//! - **Number Formatting**: With [`ParseOptions::keep_number_text`] numbers keep the text they were written as, so re-emitted files only change where values changed.
+//! - **Lossless Editing**: [`document::Document`] reads and changes values by path, e.g. `m_Renderers[0].m_flAlphaScale`, and writes the file back with its comments, blank lines and formatting untouched.
+//! - **Untrusted Input**: Nesting is limited to [`DEFAULT_MAX_DEPTH`] levels by default, so hostile files return an error instead of overflowing the stack.
+//! - **Customizable Parsing**: [`parse_kv3_with`] takes [`ParseOptions`] to choose strict or lenient parsing, a max nesting depth, the allowed comment styles, the duplicate key policy and whether the header is required. Built using the [`nom`](https://github.com/Geal/nom) parser combinator library.
 //!
 //! ## Example
 //!
@@ -37,7 +52,8 @@
 //!         println!("Parsed KV3: {:#?}", kvs);
 //!     }
 //!     Err(e) => {
-//!         eprintln!("Error parsing KV3: {:?}", e);
+//!         // e.g. "expected `=` after key `number` at line 4, column 12: `number 5`"
+//!         eprintln!("Error parsing KV3: {}", e);
 //!     }
 //! }
 //! ```
@@ -51,71 +67,907 @@
 //! This project is licensed under the MIT License.
 //!
 
+pub mod binary;
+pub mod document;
+mod error;
 #[cfg(feature = "serde")]
 pub mod kv3_serde;
+mod macros;
+mod path;
+mod select;
 
 mod test;
+mod writer;
 
-use log::{debug, error, info};
+pub use error::Error;
+pub use select::{select, Selected};
+pub use writer::{to_kv3_string, write_kv3, write_kv3_document};
+
+use error::{finish, ParseError, ParseResult};
+
+use log::{debug, error, info, warn};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while},
-    character::complete::multispace1,
-    combinator::{map, opt},
-    multi::{many0, separated_list0},
+    bytes::complete::{tag, tag_no_case, take_till, take_until, take_while, take_while1},
+    character::complete::{char, hex_digit1, multispace0, multispace1, satisfy},
+    combinator::{all_consuming, consumed, map, not, opt},
+    multi::{fold_many1, many0, separated_list0, separated_list1},
     number::complete::recognize_float,
-    sequence::{delimited, pair, preceded, separated_pair},
-    IResult,
+    sequence::{delimited, pair, preceded, terminated, tuple},
 };
-#[cfg(feature = "serde")]
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::fmt;
 
-#[cfg(feature = "serde")]
-#[derive(Debug, Serialize)]
+/// Default limit for how deeply arrays and objects may nest, see
+/// [`ParseOptions::max_depth`] and [`binary::ReadOptions::max_depth`].
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// GUID of the `text` encoding written by Valve's tools.
+pub const KV3_ENCODING_TEXT_GUID: &str = "e21c7f3c-8a33-41c5-9977-a76d3a32aa0d";
+/// GUID of the `generic` format written by Valve's tools.
+pub const KV3_FORMAT_GENERIC_GUID: &str = "7412167c-06e9-4698-aff2-e63eb59037e7";
+
+#[derive(Debug, Clone, Default)]
 pub enum KV3Value {
     Bool(bool),
     Int(i64),
+    /// An unsigned integer. Text and serde produce it only for values above
+    /// `i64::MAX`, such as 64-bit hashes; binary KV3 uses it for its unsigned
+    /// types.
+    UInt(u64),
     Double(f64),
     String(String),
     Array(Vec<KV3Value>),
     HexArray(Vec<u8>), // New variant for hexadecimal arrays
     Object(KV3Object),
+    #[default]
     Null,
+    /// A value with a flag prefix, e.g. `resource:"models/foo.vmdl"`. Flag
+    /// names without a [`KV3Flags`] constant are skipped when parsing, so
+    /// values with only such flags are read without them.
+    ///
+    /// The KV3 text and binary writers keep the flags. Serde serialization
+    /// writes only `value`, as other formats have no place for them.
+    Flagged {
+        flags: KV3Flags,
+        value: Box<KV3Value>,
+    },
+    /// A number together with the text it was written as, e.g. `5.000000`
+    /// or `0x1F`, so the writer reproduces it unchanged. Only produced with
+    /// [`ParseOptions::keep_number_text`]; `value` is an [`KV3Value::Int`],
+    /// [`KV3Value::UInt`] or [`KV3Value::Double`].
+    ///
+    /// If `value` is changed so that `text` no longer matches it, the writer
+    /// writes `value` instead.
+    Number {
+        value: Box<KV3Value>,
+        text: String,
+    },
 }
 
-#[cfg(not(feature = "serde"))]
-#[derive(Debug)]
-pub enum KV3Value {
-    Bool(bool),
-    Int(i64),
-    Double(f64),
-    String(String),
-    Array(Vec<KV3Value>),
-    HexArray(Vec<u8>), // New variant for hexadecimal arrays
-    Object(KV3Object),
-    Null,
+/// Returned by the [`Index`](std::ops::Index) impls for missing keys and
+/// indices.
+static NULL: KV3Value = KV3Value::Null;
+
+/// Typed access to values. The accessors see through [`KV3Value::Flagged`]
+/// and [`KV3Value::Number`], so `resource:"models/foo.vmdl"` is a string
+/// and a number keeps its value whichever way it was written.
+///
+/// ```rust
+/// use kv3::parse_kv3_with;
+///
+/// let input = r#"{ m_hModel = resource:"models/foo.vmdl" m_Sizes = [ 1, 2.5 ] }"#;
+/// let (_, root) = parse_kv3_with(input, &Default::default()).unwrap();
+///
+/// assert_eq!(root["m_hModel"].as_str(), Some("models/foo.vmdl"));
+/// assert_eq!(root["m_Sizes"][1].as_f64(), Some(2.5));
+/// assert!(root["m_Missing"].is_null());
+/// ```
+impl KV3Value {
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.inner() {
+            KV3Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of an integer that fits into an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self.inner() {
+            KV3Value::Int(i) => Some(*i),
+            KV3Value::UInt(u) => i64::try_from(*u).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of an integer that fits into a `u64`.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.inner() {
+            KV3Value::Int(i) => u64::try_from(*i).ok(),
+            KV3Value::UInt(u) => Some(*u),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a double, or of an integer converted to `f64`.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.inner() {
+            KV3Value::Int(i) => Some(*i as f64),
+            KV3Value::UInt(u) => Some(*u as f64),
+            KV3Value::Double(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self.inner() {
+            KV3Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<KV3Value>> {
+        match self.inner() {
+            KV3Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_array_mut(&mut self) -> Option<&mut Vec<KV3Value>> {
+        match self.inner_mut() {
+            KV3Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&KV3Object> {
+        match self.inner() {
+            KV3Value::Object(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    pub fn as_object_mut(&mut self) -> Option<&mut KV3Object> {
+        match self.inner_mut() {
+            KV3Value::Object(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// Returns the bytes of a hex array, `#[ ... ]`.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self.inner() {
+            KV3Value::HexArray(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self.inner(), KV3Value::Null)
+    }
+
+    /// The value without its flags or source text.
+    fn inner(&self) -> &KV3Value {
+        match self {
+            KV3Value::Flagged { value, .. } | KV3Value::Number { value, .. } => value.inner(),
+            value => value,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut KV3Value {
+        match self {
+            KV3Value::Flagged { value, .. } | KV3Value::Number { value, .. } => value.inner_mut(),
+            value => value,
+        }
+    }
 }
 
-#[cfg(feature = "serde")]
-#[derive(Debug, Serialize, Deserialize)]
-pub struct KV3Object {
-    fields: HashMap<String, KV3Value>,
+impl std::ops::Index<&str> for KV3Value {
+    type Output = KV3Value;
+
+    /// Returns the value of `key`, or [`KV3Value::Null`] if this is not an
+    /// object or has no such key. Repeated keys return their first value,
+    /// see [`KV3Object::get`].
+    fn index(&self, key: &str) -> &KV3Value {
+        self.as_object()
+            .and_then(|obj| obj.get(key))
+            .unwrap_or(&NULL)
+    }
+}
+
+impl std::ops::Index<usize> for KV3Value {
+    type Output = KV3Value;
+
+    /// Returns the item at `index`, or [`KV3Value::Null`] if this is not an
+    /// array or is too short.
+    fn index(&self, index: usize) -> &KV3Value {
+        self.as_array()
+            .and_then(|items| items.get(index))
+            .unwrap_or(&NULL)
+    }
+}
+
+impl PartialEq for KV3Value {
+    /// Compares values the way they read back: doubles compare by value,
+    /// except that NaN equals NaN, so every value equals itself. Integers
+    /// are equal if their values are, whether [`KV3Value::Int`] or
+    /// [`KV3Value::UInt`], and the text kept by [`KV3Value::Number`] is
+    /// ignored. Objects compare their entries regardless of order, though
+    /// the values of a repeated key must come in the same order.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (KV3Value::Number { value, .. }, other) | (other, KV3Value::Number { value, .. }) => {
+                **value == *other
+            }
+            (KV3Value::Bool(a), KV3Value::Bool(b)) => a == b,
+            (KV3Value::Int(a), KV3Value::Int(b)) => a == b,
+            (KV3Value::UInt(a), KV3Value::UInt(b)) => a == b,
+            (KV3Value::Int(i), KV3Value::UInt(u)) | (KV3Value::UInt(u), KV3Value::Int(i)) => {
+                u64::try_from(*i) == Ok(*u)
+            }
+            (KV3Value::Double(a), KV3Value::Double(b)) => a == b || (a.is_nan() && b.is_nan()),
+            (KV3Value::String(a), KV3Value::String(b)) => a == b,
+            (KV3Value::Array(a), KV3Value::Array(b)) => a == b,
+            (KV3Value::HexArray(a), KV3Value::HexArray(b)) => a == b,
+            (KV3Value::Object(a), KV3Value::Object(b)) => a == b,
+            (KV3Value::Null, KV3Value::Null) => true,
+            (
+                KV3Value::Flagged {
+                    flags: flags_a,
+                    value: value_a,
+                },
+                KV3Value::Flagged {
+                    flags: flags_b,
+                    value: value_b,
+                },
+            ) => flags_a == flags_b && value_a == value_b,
+            _ => false,
+        }
+    }
+}
+
+impl From<bool> for KV3Value {
+    fn from(b: bool) -> Self {
+        KV3Value::Bool(b)
+    }
+}
+
+macro_rules! impl_from_signed {
+    ($($ty:ty),*) => {$(
+        impl From<$ty> for KV3Value {
+            fn from(i: $ty) -> Self {
+                KV3Value::Int(i as i64)
+            }
+        }
+    )*};
+}
+
+impl_from_signed!(i8, i16, i32, i64, isize);
+
+impl From<u16> for KV3Value {
+    fn from(u: u16) -> Self {
+        KV3Value::Int(u.into())
+    }
+}
+
+impl From<u32> for KV3Value {
+    fn from(u: u32) -> Self {
+        KV3Value::Int(u.into())
+    }
+}
+
+impl From<u64> for KV3Value {
+    /// Values above `i64::MAX` become a [`KV3Value::UInt`].
+    fn from(u: u64) -> Self {
+        i64::try_from(u).map_or(KV3Value::UInt(u), KV3Value::Int)
+    }
+}
+
+impl From<usize> for KV3Value {
+    fn from(u: usize) -> Self {
+        KV3Value::from(u as u64)
+    }
 }
 
-#[cfg(not(feature = "serde"))]
-#[allow(dead_code)]
-#[derive(Debug)]
+impl From<f32> for KV3Value {
+    fn from(d: f32) -> Self {
+        KV3Value::Double(d.into())
+    }
+}
+
+impl From<f64> for KV3Value {
+    fn from(d: f64) -> Self {
+        KV3Value::Double(d)
+    }
+}
+
+impl From<String> for KV3Value {
+    fn from(s: String) -> Self {
+        KV3Value::String(s)
+    }
+}
+
+impl From<&str> for KV3Value {
+    fn from(s: &str) -> Self {
+        KV3Value::String(s.to_string())
+    }
+}
+
+impl From<KV3Object> for KV3Value {
+    fn from(obj: KV3Object) -> Self {
+        KV3Value::Object(obj)
+    }
+}
+
+impl<T: Into<KV3Value>> From<Vec<T>> for KV3Value {
+    fn from(items: Vec<T>) -> Self {
+        KV3Value::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Vec<u8>> for KV3Value {
+    /// Bytes become a hex array, `#[ ... ]`. `u8` has no `From` impl of its
+    /// own, so a `Vec<u8>` is never an array of integers.
+    fn from(bytes: Vec<u8>) -> Self {
+        KV3Value::HexArray(bytes)
+    }
+}
+
+impl<K: Into<String>, V: Into<KV3Value>, S> From<std::collections::HashMap<K, V, S>> for KV3Value {
+    fn from(map: std::collections::HashMap<K, V, S>) -> Self {
+        let mut obj = KV3Object::default();
+        for (key, value) in map {
+            obj.insert(key, value.into());
+        }
+        KV3Value::Object(obj)
+    }
+}
+
+#[cfg(feature = "preserve_order")]
+impl<K: Into<String>, V: Into<KV3Value>, S> From<indexmap::IndexMap<K, V, S>> for KV3Value {
+    fn from(map: indexmap::IndexMap<K, V, S>) -> Self {
+        let mut obj = KV3Object::default();
+        for (key, value) in map {
+            obj.insert(key, value.into());
+        }
+        KV3Value::Object(obj)
+    }
+}
+
+/// Flags that can prefix a value, e.g. `resource:` or `resource_name|subclass:`.
+///
+/// The bits match the flag byte used by binary KV3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct KV3Flags(u8);
+
+impl KV3Flags {
+    pub const NONE: KV3Flags = KV3Flags(0);
+    pub const RESOURCE: KV3Flags = KV3Flags(1);
+    pub const RESOURCE_NAME: KV3Flags = KV3Flags(1 << 1);
+    /// `panel:`, stored in the bit between `resource_name` and `panorama`.
+    pub const PANEL: KV3Flags = KV3Flags(1 << 2);
+    pub const PANORAMA: KV3Flags = KV3Flags(1 << 3);
+    pub const SOUNDEVENT: KV3Flags = KV3Flags(1 << 4);
+    pub const SUBCLASS: KV3Flags = KV3Flags(1 << 5);
+
+    const NAMES: [(KV3Flags, &'static str); 6] = [
+        (KV3Flags::RESOURCE, "resource"),
+        (KV3Flags::RESOURCE_NAME, "resource_name"),
+        (KV3Flags::PANEL, "panel"),
+        (KV3Flags::PANORAMA, "panorama"),
+        (KV3Flags::SOUNDEVENT, "soundevent"),
+        (KV3Flags::SUBCLASS, "subclass"),
+    ];
+
+    /// Creates flags from raw bits, keeping bits without a known name.
+    pub const fn from_bits_retain(bits: u8) -> Self {
+        KV3Flags(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: KV3Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags that have a text name, without any other bits.
+    pub const fn named(self) -> Self {
+        let known = KV3Flags::RESOURCE.0
+            | KV3Flags::RESOURCE_NAME.0
+            | KV3Flags::PANEL.0
+            | KV3Flags::PANORAMA.0
+            | KV3Flags::SOUNDEVENT.0
+            | KV3Flags::SUBCLASS.0;
+        KV3Flags(self.0 & known)
+    }
+
+    /// Looks up a single flag by its text name, e.g. `soundevent`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        KV3Flags::NAMES
+            .iter()
+            .find(|(_, flag_name)| *flag_name == name)
+            .map(|(flag, _)| *flag)
+    }
+}
+
+impl std::ops::BitOr for KV3Flags {
+    type Output = KV3Flags;
+
+    fn bitor(self, rhs: KV3Flags) -> KV3Flags {
+        KV3Flags(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for KV3Flags {
+    fn bitor_assign(&mut self, rhs: KV3Flags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl fmt::Display for KV3Flags {
+    /// Writes the flag names joined by `|`, e.g. `resource_name|subclass`.
+    /// Bits without a name are not written.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = KV3Flags::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name);
+        for (i, name) in names.enumerate() {
+            if i > 0 {
+                f.write_str("|")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Map backing [`KV3Object`] and the root returned by [`parse_kv3`].
+///
+/// With the `preserve_order` feature (enabled by default) keys keep the order
+/// they appeared in, so parsed files are written back in the same order.
+#[cfg(feature = "preserve_order")]
+pub type KV3Map = indexmap::IndexMap<String, KV3Value>;
+
+/// Map backing [`KV3Object`] and the root returned by [`parse_kv3`].
+///
+/// Enable the `preserve_order` feature to keep keys in the order they
+/// appeared in.
+#[cfg(not(feature = "preserve_order"))]
+pub type KV3Map = std::collections::HashMap<String, KV3Value>;
+
+/// The entries of a KV3 object. Build one with [`KV3Object::default`] and
+/// [`KV3Object::insert`], or with the [`kv3!`] macro.
+#[derive(Debug, Clone, Default)]
 pub struct KV3Object {
-    fields: HashMap<String, KV3Value>,
+    fields: KV3Map,
+    /// Repeated keys kept by [`DuplicateKeys::PreserveAll`], with their
+    /// position among all entries of the object.
+    duplicates: Vec<(usize, String, KV3Value)>,
+}
+
+impl KV3Object {
+    pub(crate) fn new(fields: KV3Map) -> Self {
+        KV3Object {
+            fields,
+            duplicates: Vec::new(),
+        }
+    }
+
+    /// Returns the value of `key`. For keys repeated under
+    /// [`DuplicateKeys::PreserveAll`] this is the first value, while
+    /// [`KV3Object::iter`] and [`KV3Object::len`] include every repeat.
+    pub fn get(&self, key: &str) -> Option<&KV3Value> {
+        self.fields.get(key)
+    }
+
+    /// Returns the value of `key` mutably, see [`KV3Object::get`].
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut KV3Value> {
+        self.fields.get_mut(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.fields.contains_key(key)
+    }
+
+    /// Sets `key` to `value` and returns the previous value. An existing key
+    /// keeps its position and loses any repeated values.
+    pub fn insert(&mut self, key: impl Into<String>, value: KV3Value) -> Option<KV3Value> {
+        let key = key.into();
+        self.drop_entries(&key, false);
+        self.fields.insert(key, value)
+    }
+
+    /// Removes `key`, including any repeated values, and returns its value.
+    pub fn remove(&mut self, key: &str) -> Option<KV3Value> {
+        self.drop_entries(key, true);
+        #[cfg(feature = "preserve_order")]
+        return self.fields.shift_remove(key);
+        #[cfg(not(feature = "preserve_order"))]
+        return self.fields.remove(key);
+    }
+
+    /// Number of entries, counting repeated keys.
+    pub fn len(&self) -> usize {
+        self.fields.len() + self.duplicates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Iterates over the keys in order, including repeated keys.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Iterates over the entries in order, including repeated keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &KV3Value)> {
+        let mut fields = self.fields.iter();
+        let mut duplicates = self.duplicates.iter().peekable();
+        let mut position = 0;
+        std::iter::from_fn(move || {
+            let entry = match duplicates.next_if(|(at, _, _)| *at == position) {
+                Some((_, key, value)) => Some((key, value)),
+                None => fields.next(),
+            };
+            position += 1;
+            entry
+        })
+    }
+
+    /// Drops the repeated values of `key`, and its first entry too if
+    /// `with_first` is set, keeping the positions of other repeated keys in
+    /// step. The first entry itself stays in `fields`.
+    fn drop_entries(&mut self, key: &str, with_first: bool) {
+        if self.duplicates.is_empty() {
+            return;
+        }
+
+        let mut positions = self
+            .iter()
+            .enumerate()
+            .filter(|(_, (k, _))| k.as_str() == key)
+            .map(|(i, _)| i);
+        if !with_first {
+            positions.next();
+        }
+        let removed: Vec<usize> = positions.collect();
+
+        self.duplicates.retain(|(_, k, _)| k != key);
+        for (position, _, _) in &mut self.duplicates {
+            *position -= removed.iter().filter(|at| **at < *position).count();
+        }
+    }
+
+    /// Repeated values of each key, in the order they appeared.
+    fn duplicates_by_key(&self) -> Vec<(&String, &KV3Value)> {
+        let mut duplicates: Vec<_> = self.duplicates.iter().map(|(_, k, v)| (k, v)).collect();
+        duplicates.sort_by_key(|(key, _)| *key);
+        duplicates
+    }
+}
+
+impl PartialEq for KV3Object {
+    /// Compares the entries regardless of order. The values of a repeated
+    /// key are compared in the order they appeared.
+    fn eq(&self, other: &Self) -> bool {
+        self.fields == other.fields && self.duplicates_by_key() == other.duplicates_by_key()
+    }
+}
+
+/// The header line of a KV3 document, e.g.
+/// `<!-- kv3 encoding:text:version{...} format:generic:version{...} -->`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KV3Header {
+    /// Encoding name, e.g. `text`.
+    pub encoding: String,
+    /// Encoding version GUID, without the surrounding braces.
+    pub encoding_guid: String,
+    /// Format name, e.g. `generic`, `vpcf` or `modeldoc28`.
+    pub format: String,
+    /// Format version GUID, without the surrounding braces.
+    pub format_guid: String,
+}
+
+impl Default for KV3Header {
+    /// The `text` / `generic` header Valve's tools emit for plain KV3 files.
+    fn default() -> Self {
+        KV3Header {
+            encoding: "text".to_string(),
+            encoding_guid: KV3_ENCODING_TEXT_GUID.to_string(),
+            format: "generic".to_string(),
+            format_guid: KV3_FORMAT_GENERIC_GUID.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for KV3Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<!-- kv3 encoding:{}:version{{{}}} format:{}:version{{{}}} -->",
+            self.encoding, self.encoding_guid, self.format, self.format_guid
+        )
+    }
+}
+
+/// What the parser does with a key that appears more than once in the same
+/// object.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    /// Fail with an [`Error::Syntax`] pointing at the repeated key.
+    Error,
+    /// Keep the first value and ignore later ones.
+    FirstWins,
+    /// Keep the last value, at the position of the first.
+    #[default]
+    LastWins,
+    /// Keep every value in input order, so the object is written back with
+    /// the key repeated. Lookups by key see the first value.
+    PreserveAll,
+}
+
+/// Comment styles accepted between tokens. All of them are allowed by
+/// default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommentStyles {
+    /// Single-line comments, `// ...`.
+    pub line: bool,
+    /// Multi-line comments, `/* ... */`.
+    pub block: bool,
+    /// XML-style comments, `<!-- ... -->`. The document header is parsed
+    /// separately and is allowed either way.
+    pub xml: bool,
+}
+
+impl Default for CommentStyles {
+    fn default() -> Self {
+        CommentStyles {
+            line: true,
+            block: true,
+            xml: true,
+        }
+    }
+}
+
+/// Options for [`parse_kv3_with`].
+///
+/// The defaults match [`parse_kv3`]: lenient parsing with nesting limited to
+/// [`DEFAULT_MAX_DEPTH`], all comment styles, the last value wins for
+/// repeated keys and the header is optional. The fields can be set directly
+/// or chained:
+///
+/// ```rust
+/// use kv3::{DuplicateKeys, ParseOptions};
+///
+/// let options = ParseOptions::new()
+///     .strict(true)
+///     .max_depth(64)
+///     .duplicate_keys(DuplicateKeys::Error)
+///     .require_header(true);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Reject input the parser otherwise accepts for compatibility with
+    /// hand-written files: unknown escape sequences in strings, which are
+    /// kept as written, and hex array entries that are not two-digit bytes,
+    /// which are skipped.
+    pub strict: bool,
+    /// How deeply arrays and objects may nest, counting the root object as
+    /// 1. Deeper input is an error instead of overflowing the stack.
+    ///
+    /// `None` removes the limit, which is only safe for trusted input.
+    pub max_depth: Option<usize>,
+    /// Comment styles allowed in the input.
+    pub comments: CommentStyles,
+    /// How keys repeated within an object are handled.
+    pub duplicate_keys: DuplicateKeys,
+    /// Require a `<!-- kv3 encoding:... format:... -->` header before the
+    /// root object.
+    pub require_header: bool,
+    /// Keep the text of numbers as [`KV3Value::Number`], so unchanged
+    /// numbers are written back byte-for-byte, e.g. `5.000000` instead of
+    /// `5.0`.
+    pub keep_number_text: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            strict: false,
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            comments: CommentStyles::default(),
+            duplicate_keys: DuplicateKeys::default(),
+            require_header: false,
+            keep_number_text: false,
+        }
+    }
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        ParseOptions::default()
+    }
+
+    /// Sets [`ParseOptions::strict`].
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Limits nesting to `max_depth` levels, see [`ParseOptions::max_depth`].
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Sets [`ParseOptions::comments`].
+    pub fn comments(mut self, comments: CommentStyles) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    /// Sets [`ParseOptions::duplicate_keys`].
+    pub fn duplicate_keys(mut self, duplicate_keys: DuplicateKeys) -> Self {
+        self.duplicate_keys = duplicate_keys;
+        self
+    }
+
+    /// Sets [`ParseOptions::require_header`].
+    pub fn require_header(mut self, require_header: bool) -> Self {
+        self.require_header = require_header;
+        self
+    }
+
+    /// Sets [`ParseOptions::keep_number_text`].
+    pub fn keep_number_text(mut self, keep_number_text: bool) -> Self {
+        self.keep_number_text = keep_number_text;
+        self
+    }
+}
+
+/// Parses a KV3 document that starts with a `<!-- kv3 ... -->` header and
+/// returns the header together with the root object.
+///
+/// The whole input must be consumed: anything but whitespace and comments
+/// after the root object, such as a second root or a stray `}`, is an error.
+/// Use [`parse_kv3`] to parse a root object and continue after it.
+///
+/// ```rust
+/// use kv3::parse_kv3_document;
+///
+/// let input = r#"<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:vpcf26:version{26288658-411e-4f14-b698-2e1e5d00dec6} -->
+/// {
+///     m_nMaxParticles = 16
+/// }
+/// "#;
+///
+/// let (header, _root) = parse_kv3_document(input).unwrap();
+/// assert_eq!(header.format, "vpcf26");
+/// ```
+pub fn parse_kv3_document(input: &str) -> Result<(KV3Header, KV3Value), Error> {
+    let (header, root) = parse_kv3_with(input, &ParseOptions::new().require_header(true))?;
+    // The header is required, so it is always there
+    Ok((header.unwrap_or_default(), root))
+}
+
+/// Parses a KV3 document using the given [`ParseOptions`] and returns its
+/// header, if any, together with the root object.
+///
+/// Like [`parse_kv3_document`], the whole input must be consumed.
+///
+/// ```rust
+/// use kv3::{parse_kv3_with, DuplicateKeys, ParseOptions};
+///
+/// let options = ParseOptions::new().duplicate_keys(DuplicateKeys::Error);
+/// assert!(parse_kv3_with("{ a = 1 b = 2 }", &options).is_ok());
+/// assert!(parse_kv3_with("{ a = 1 a = 2 }", &options).is_err());
+/// ```
+pub fn parse_kv3_with(
+    input: &str,
+    options: &ParseOptions,
+) -> Result<(Option<KV3Header>, KV3Value), Error> {
+    finish(input, parse_document(input, options))
+        .map(|(_, (header, root))| (header, KV3Value::Object(root)))
+}
+
+fn parse_document<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+) -> ParseResult<'a, (Option<KV3Header>, KV3Object)> {
+    info!("Parsing KV3 document...");
+
+    let header = |input| {
+        if options.require_header {
+            map(
+                expect(
+                    "a `<!-- kv3 encoding:... format:... -->` header",
+                    parse_header,
+                ),
+                Some,
+            )(input)
+        } else {
+            opt(parse_header)(input)
+        }
+    };
+
+    all_consuming(pair(header, |input| parse_root(input, options)))(input)
+}
+
+fn parse_header(input: &str) -> ParseResult<'_, KV3Header> {
+    info!("Parsing KV3 header...");
+
+    let result = delimited(
+        tuple((multispace0, tag("<!--"), multispace0, tag("kv3"))),
+        pair(
+            preceded(multispace1, parse_header_entry("encoding")),
+            preceded(multispace1, parse_header_entry("format")),
+        ),
+        preceded(multispace0, tag("-->")),
+    )(input);
+
+    result.map(
+        |(remaining, ((encoding, encoding_guid), (format, format_guid)))| {
+            debug!(
+                "Parsed header: encoding = {}, format = {}",
+                encoding, format
+            );
+            (
+                remaining,
+                KV3Header {
+                    encoding,
+                    encoding_guid,
+                    format,
+                    format_guid,
+                },
+            )
+        },
+    )
+}
+
+/// Parses `<name>:<value>:version{<guid>}`, returning `(value, guid)`.
+fn parse_header_entry<'a>(
+    name: &'static str,
+) -> impl FnMut(&'a str) -> ParseResult<'a, (String, String)> {
+    map(
+        tuple((
+            tag(name),
+            char(':'),
+            take_while1(|c: char| c != ':' && !c.is_whitespace()),
+            tag(":version{"),
+            terminated(take_until("}"), char('}')),
+        )),
+        |(_, _, value, _, guid): (&str, char, &str, &str, &str)| {
+            (value.to_string(), guid.to_string())
+        },
+    )
 }
 
-pub fn parse_kv3(input: &str) -> IResult<&str, HashMap<String, KV3Value>> {
+/// Parses the root object of a KV3 document without a header and returns
+/// its fields together with the input after it.
+///
+/// Parsing stops after the root object; see [`parse_kv3_document`] for a
+/// parser that rejects trailing input, and [`parse_kv3_with`] to configure
+/// parsing.
+pub fn parse_kv3(input: &str) -> Result<(&str, KV3Map), Error> {
+    finish(input, parse_root(input, &ParseOptions::default()))
+        .map(|(remaining, root)| (remaining, root.fields))
+}
+
+fn parse_root<'a>(input: &'a str, options: &ParseOptions) -> ParseResult<'a, KV3Object> {
     info!("Parsing KV3 root...");
 
-    let (remaining, _) = ws(tag("{"))(input)?;
-    let (remaining, kvs) = many0(ws(parse_key_value))(remaining)?;
-    let (remaining, _) = ws(tag("}"))(remaining)?;
+    let (open, _) = skip_comments_and_whitespace(input, options)?;
+    let (remaining, _) = ws(options, expect("`{` to open the root object", tag("{")))(open)?;
+    let (_, depth) = nested(open, options, 0)?;
+    let (remaining, kvs) = parse_fields(remaining, options, depth)?;
+    let (remaining, _) = ws(options, expect("a key or `}`", tag("}")))(remaining)?;
 
     debug!(
         "Parsed KV3 root successfully: {:?}",
@@ -123,27 +975,79 @@ pub fn parse_kv3(input: &str) -> IResult<&str, HashMap<String, KV3Value>> {
     );
     debug!("Remaining input: {}", truncate_str(remaining, 100));
 
-    Ok((remaining, kvs.into_iter().collect()))
+    Ok((remaining, kvs))
 }
 
+/// Parses the key-value pairs of an object, up to its closing `}`.
+fn parse_fields<'a>(
+    mut input: &'a str,
+    options: &ParseOptions,
+    depth: usize,
+) -> ParseResult<'a, KV3Object> {
+    let mut object = KV3Object::new(KV3Map::default());
+
+    loop {
+        let (start, _) = skip_comments_and_whitespace(input, options)?;
+        match parse_key_value(start, options, depth) {
+            Ok((remaining, (key, value))) => {
+                let position = object.len();
+                match (object.fields.get_mut(&key), options.duplicate_keys) {
+                    (None, _) => {
+                        object.fields.insert(key, value);
+                    }
+                    (Some(_), DuplicateKeys::Error) => {
+                        let expected = format!("a unique key, `{}` is already set", key);
+                        return Err(nom::Err::Failure(ParseError::new(start, expected)));
+                    }
+                    (Some(_), DuplicateKeys::FirstWins) => {}
+                    (Some(existing), DuplicateKeys::LastWins) => *existing = value,
+                    (Some(_), DuplicateKeys::PreserveAll) => {
+                        object.duplicates.push((position, key, value));
+                    }
+                }
+                input = remaining;
+            }
+            Err(nom::Err::Error(_)) => return Ok((input, object)),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Shortens `input` to at most `max_length` characters for logging.
 fn truncate_str(input: &str, max_length: usize) -> String {
-    if input.len() > max_length {
-        format!("{}... (truncated)", &input[..max_length])
-    } else {
-        input.to_string()
+    match input.char_indices().nth(max_length) {
+        Some((end, _)) => format!("{}... (truncated)", &input[..end]),
+        None => input.to_string(),
+    }
+}
+
+/// Checks that an array or object opening at `input` inside a container at
+/// `depth` stays within [`ParseOptions::max_depth`], returning its depth.
+fn nested<'a>(input: &'a str, options: &ParseOptions, depth: usize) -> ParseResult<'a, usize> {
+    let depth = depth + 1;
+    match options.max_depth {
+        Some(max_depth) if depth > max_depth => {
+            let expected = format!("at most {} levels of nesting", max_depth);
+            Err(nom::Err::Failure(ParseError::new(input, expected)))
+        }
+        _ => Ok((input, depth)),
     }
 }
 
-fn parse_comment(input: &str) -> IResult<&str, ()> {
+fn parse_comment<'a>(input: &'a str, styles: &CommentStyles) -> ParseResult<'a, ()> {
     // Parse single-line comments (// ...)
     let single_line = map(
-        preceded(tag("//"), take_until("\n")),
+        preceded(tag("//"), take_till(|c| c == '\n')),
         |_| (), // Ignore content
     );
 
     // Parse multi-line comments (/* ... */)
     let multi_line = map(
-        delimited(tag("/*"), take_until("*/"), tag("*/")),
+        delimited(
+            tag("/*"),
+            expect("`*/` to close the comment", take_until("*/")),
+            tag("*/"),
+        ),
         |_| (), // Ignore content
     );
 
@@ -153,30 +1057,67 @@ fn parse_comment(input: &str) -> IResult<&str, ()> {
         |_| (), // Ignore content
     );
 
-    // Combine all comment formats
-    alt((single_line, multi_line, xml_style))(input)
+    // Combine the allowed comment formats
+    alt((
+        when(styles.line, single_line),
+        when(styles.block, multi_line),
+        when(styles.xml, xml_style),
+    ))(input)
 }
 
-fn skip_comments_and_whitespace(input: &str) -> IResult<&str, ()> {
+fn skip_comments_and_whitespace<'a>(input: &'a str, options: &ParseOptions) -> ParseResult<'a, ()> {
     map(
-        many0(alt((map(multispace1, |_| ()), parse_comment))),
+        many0(alt((map(multispace1, |_| ()), |input| {
+            parse_comment(input, &options.comments)
+        }))),
         |_| (),
     )(input)
 }
 
-fn ws<'a, F, O>(inner: F) -> impl Fn(&'a str) -> IResult<&'a str, O>
+fn ws<'a, 'o, F, O>(
+    options: &'o ParseOptions,
+    inner: F,
+) -> impl Fn(&'a str) -> ParseResult<'a, O> + 'o
 where
-    F: 'a + Fn(&'a str) -> IResult<&'a str, O>,
+    F: Fn(&'a str) -> ParseResult<'a, O> + 'o,
 {
     move |input: &str| {
-        let (input, _) = skip_comments_and_whitespace(input)?;
+        let (input, _) = skip_comments_and_whitespace(input, options)?;
         let (input, res) = inner(input)?;
-        let (input, _) = skip_comments_and_whitespace(input)?;
+        let (input, _) = skip_comments_and_whitespace(input, options)?;
         Ok((input, res))
     }
 }
 
-fn parse_number_or_float(input: &str) -> IResult<&str, KV3Value> {
+/// Runs `inner` if `enabled`, otherwise fails without consuming input.
+fn when<'a, F, O>(enabled: bool, mut inner: F) -> impl FnMut(&'a str) -> ParseResult<'a, O>
+where
+    F: FnMut(&'a str) -> ParseResult<'a, O>,
+{
+    move |input: &str| {
+        if enabled {
+            inner(input)
+        } else {
+            Err(nom::Err::Error(ParseError::new(input, "a value")))
+        }
+    }
+}
+
+/// Runs `inner` and turns a recoverable error into a failure that reports
+/// `expected` at the current position, so no other alternative is tried.
+fn expect<'a, F, O>(expected: &'static str, inner: F) -> impl Fn(&'a str) -> ParseResult<'a, O>
+where
+    F: Fn(&'a str) -> ParseResult<'a, O>,
+{
+    move |input: &str| {
+        inner(input).map_err(|err| match err {
+            nom::Err::Error(_) => nom::Err::Failure(ParseError::new(input, expected)),
+            err => err,
+        })
+    }
+}
+
+fn parse_number_or_float(input: &str) -> ParseResult<'_, KV3Value> {
     let input = input.trim_start(); // Trim leading whitespace
 
     let (remaining, num_str) = recognize_float(input)?;
@@ -185,23 +1126,125 @@ fn parse_number_or_float(input: &str) -> IResult<&str, KV3Value> {
         num_str
             .parse::<f64>()
             .map(|v| (remaining, KV3Value::Double(v)))
-            .map_err(|_| {
-                nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Float))
-            })
+            .map_err(|_| nom::Err::Failure(ParseError::new(input, "a valid number")))
     } else {
-        // Parse as integer
-        num_str
-            .parse::<i64>()
-            .map(|v| (remaining, KV3Value::Int(v)))
-            .map_err(|_| {
-                nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Digit))
-            })
+        // Parse as integer, falling back to unsigned for values above i64::MAX
+        if let Ok(v) = num_str.parse::<i64>() {
+            Ok((remaining, KV3Value::Int(v)))
+        } else {
+            num_str
+                .parse::<u64>()
+                .map(|v| (remaining, KV3Value::UInt(v)))
+                .map_err(|_| {
+                    nom::Err::Failure(ParseError::new(input, "an integer in the 64-bit range"))
+                })
+        }
+    }
+}
+
+fn parse_number<'a>(input: &'a str, options: &ParseOptions) -> ParseResult<'a, KV3Value> {
+    let (remaining, (text, value)) = consumed(parse_number_literal)(input)?;
+
+    if options.keep_number_text {
+        let number = KV3Value::Number {
+            value: Box::new(value),
+            text: text.trim_start().to_string(),
+        };
+        Ok((remaining, number))
+    } else {
+        Ok((remaining, value))
     }
 }
 
-fn parse_key_value(input: &str) -> IResult<&str, (String, KV3Value)> {
+fn parse_number_literal(input: &str) -> ParseResult<'_, KV3Value> {
+    alt((parse_hex_integer, parse_non_finite, parse_number_or_float))(input)
+}
+
+/// Parses the text of a [`KV3Value::Number`], which must be a single number.
+pub(crate) fn parse_number_text(text: &str) -> Option<KV3Value> {
+    all_consuming(parse_number_literal)(text)
+        .ok()
+        .map(|(_, value)| value)
+}
+
+/// Parses a hexadecimal integer such as `0x1F` or `-0x10`.
+fn parse_hex_integer(input: &str) -> ParseResult<'_, KV3Value> {
+    let (remaining, (sign, digits)) = pair(
+        opt(alt((char('+'), char('-')))),
+        preceded(tag_no_case("0x"), expect("hex digits", hex_digit1)),
+    )(input)?;
+
+    let out_of_range =
+        || nom::Err::Failure(ParseError::new(input, "an integer in the 64-bit range"));
+    let magnitude = u64::from_str_radix(digits, 16).map_err(|_| out_of_range())?;
+    let value = if sign == Some('-') {
+        KV3Value::Int(
+            0i64.checked_sub_unsigned(magnitude)
+                .ok_or_else(out_of_range)?,
+        )
+    } else {
+        i64::try_from(magnitude).map_or(KV3Value::UInt(magnitude), KV3Value::Int)
+    };
+
+    Ok((remaining, value))
+}
+
+/// Parses `inf`, `infinity` and `nan` in any case, with an optional sign.
+fn parse_non_finite(input: &str) -> ParseResult<'_, KV3Value> {
+    let (remaining, (sign, value)) = pair(
+        opt(alt((char('+'), char('-')))),
+        terminated(
+            alt((
+                map(tag_no_case("infinity"), |_| f64::INFINITY),
+                map(tag_no_case("inf"), |_| f64::INFINITY),
+                map(tag_no_case("nan"), |_| f64::NAN),
+            )),
+            // Not the start of a longer word
+            not(satisfy(is_key_char)),
+        ),
+    )(input)?;
+
+    let value = if sign == Some('-') { -value } else { value };
+    Ok((remaining, KV3Value::Double(value)))
+}
+
+fn parse_key_value<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+    depth: usize,
+) -> ParseResult<'a, (String, KV3Value)> {
     debug!("Parsing key-value pair...");
-    let result = separated_pair(ws(parse_key), ws(tag("=")), ws(parse_value))(input);
+
+    let key = |input| parse_key(input, options);
+    let result = ws(options, key)(input).and_then(|(remaining, key)| {
+        let (start, _) = skip_comments_and_whitespace(input, options)?;
+        // A quoted empty key is no more a key than a missing one
+        let missing_key = key.is_empty();
+
+        let (remaining, _) = match ws(options, tag("="))(remaining) {
+            // `= 5` without a key in front
+            Ok(_) if missing_key => {
+                return Err(nom::Err::Failure(ParseError::new(
+                    start,
+                    "a key before `=`",
+                )));
+            }
+            Err(nom::Err::Error(err)) if !missing_key => {
+                let expected = format!("`=` after key `{}`", key);
+                return Err(nom::Err::Failure(ParseError::new(err.input, expected)));
+            }
+            // Without a key this is not a key-value pair, e.g. the closing `}`
+            result => result?,
+        };
+        match ws(options, |input| parse_value(input, options, depth))(remaining) {
+            Ok((remaining, value)) => Ok((remaining, (key, value))),
+            Err(nom::Err::Error(err)) => {
+                let expected = format!("a value for key `{}`", key);
+                Err(nom::Err::Failure(ParseError::new(err.input, expected)))
+            }
+            Err(err) => Err(err),
+        }
+    });
 
     match &result {
         Ok((remaining, (key, value))) => {
@@ -226,12 +1269,18 @@ fn parse_key_value(input: &str) -> IResult<&str, (String, KV3Value)> {
     result
 }
 
-fn parse_key(input: &str) -> IResult<&str, String> {
+/// Characters allowed in unquoted keys.
+pub(crate) fn is_key_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | ':')
+}
+
+fn parse_key<'a>(input: &'a str, options: &ParseOptions) -> ParseResult<'a, String> {
     info!("Parsing key...");
-    let result = map(
-        take_while(|c: char| c.is_alphanumeric() || c == '_'),
-        |s: &str| s.to_string(),
-    )(input);
+    let result = alt((
+        // Quoted keys may contain anything a single-line string can
+        delimited(tag("\""), |input| parse_escaped(input, options), tag("\"")),
+        map(take_while(is_key_char), |s: &str| s.to_string()),
+    ))(input);
 
     match &result {
         Ok((remaining, key)) => {
@@ -249,27 +1298,117 @@ fn parse_key(input: &str) -> IResult<&str, String> {
     result
 }
 
-fn parse_value(input: &str) -> IResult<&str, KV3Value> {
+fn parse_value<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+    depth: usize,
+) -> ParseResult<'a, KV3Value> {
     alt((
-        parse_array, // Prioritize array parsing
-        parse_hex_array,
-        parse_object,
+        |input| parse_array(input, options, depth), // Prioritize array parsing
+        |input| parse_hex_array(input, options),
+        |input| parse_object(input, options, depth),
         map(tag("false"), |_| KV3Value::Bool(false)),
         map(tag("true"), |_| KV3Value::Bool(true)),
         map(tag("null"), |_| KV3Value::Null),
-        parse_number_or_float,               // Parse numbers
-        map(parse_string, KV3Value::String), // Parse strings last
+        |input| parse_flagged(input, options, depth),
+        |input| parse_number(input, options), // Parse numbers
+        // Parse strings last
+        map(|input| parse_string(input, options), KV3Value::String),
     ))(input)
 }
 
-fn parse_string(input: &str) -> IResult<&str, String> {
+fn parse_flagged<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+    depth: usize,
+) -> ParseResult<'a, KV3Value> {
+    info!("Parsing flagged value...");
+
+    // Any name before a `:` is a flag, so unknown ones are skipped rather
+    // than failing the whole document
+    let parse_flag = map(
+        take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+        |name| {
+            KV3Flags::from_name(name).unwrap_or_else(|| {
+                warn!("Skipping unknown flag `{}`", name);
+                KV3Flags::NONE
+            })
+        },
+    );
+    let parse_flags = map(separated_list1(char('|'), parse_flag), |flags| {
+        flags
+            .into_iter()
+            .fold(KV3Flags::NONE, |acc, flag| acc | flag)
+    });
+
+    // Chained prefixes such as `resource:soundevent:` are combined, as binary
+    // KV3 has a single set of flags per value
+    let parse_prefixes = fold_many1(
+        terminated(
+            parse_flags,
+            pair(char(':'), |input| {
+                skip_comments_and_whitespace(input, options)
+            }),
+        ),
+        || KV3Flags::NONE,
+        |acc, flags| acc | flags,
+    );
+
+    let result = pair(
+        parse_prefixes,
+        expect("a value after the flags", |input| {
+            parse_value(input, options, depth)
+        }),
+    )(input);
+
+    if let Ok((_, (flags, value))) = &result {
+        debug!(
+            "Parsed flagged value: flags = {}, value = {:?}",
+            flags,
+            truncate_str(&format!("{:?}", value), 50)
+        );
+    }
+
+    result.map(|(remaining, (flags, value))| {
+        if flags.is_empty() {
+            return (remaining, value);
+        }
+        (
+            remaining,
+            KV3Value::Flagged {
+                flags,
+                value: Box::new(value),
+            },
+        )
+    })
+}
+
+fn parse_string<'a>(input: &'a str, options: &ParseOptions) -> ParseResult<'a, String> {
     info!("Parsing string...");
 
-    // Parser for multiline strings
-    let parse_multiline_string = delimited(tag("\"\"\""), take_until("\"\"\""), tag("\"\"\""));
+    // Parser for multiline strings, the newlines right after the opening and
+    // right before the closing quotes are not part of the string
+    let parse_multiline_string = map(
+        delimited(
+            tag("\"\"\""),
+            expect("`\"\"\"` to close the string", take_until("\"\"\"")),
+            tag("\"\"\""),
+        ),
+        |s: &str| {
+            let s = s
+                .strip_prefix("\r\n")
+                .or_else(|| s.strip_prefix('\n'))
+                .unwrap_or(s);
+            s.strip_suffix("\r\n")
+                .or_else(|| s.strip_suffix('\n'))
+                .unwrap_or(s)
+                .to_string()
+        },
+    );
 
-    // Parser for single-line strings
-    let parse_single_line_string = delimited(tag("\""), take_until("\""), tag("\""));
+    // Parser for single-line strings, which may contain escape sequences
+    let parse_single_line_string =
+        delimited(tag("\""), |input| parse_escaped(input, options), tag("\""));
 
     // Try to parse a multiline string first, then a single-line string
     let result = alt((parse_multiline_string, parse_single_line_string))(input);
@@ -279,25 +1418,78 @@ fn parse_string(input: &str) -> IResult<&str, String> {
         debug!("Remaining input: {}", truncate_str(remaining, 200));
     }
 
-    result.map(|(remaining, s)| (remaining, s.to_string()))
+    result
 }
 
-fn parse_array(input: &str) -> IResult<&str, KV3Value> {
+/// Parses the contents of a single-line string up to the closing quote and
+/// decodes the `\"`, `\\`, `\n`, `\t` and `\r` escape sequences. Other
+/// backslashes are kept as written, e.g. in `"C:\Steam"`, unless
+/// [`ParseOptions::strict`] is set.
+fn parse_escaped<'a>(input: &'a str, options: &ParseOptions) -> ParseResult<'a, String> {
+    let mut string = String::new();
+    let mut chars = input.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((&input[i..], string)),
+            '\\' => match chars.next() {
+                Some((_, '"')) => string.push('"'),
+                Some((_, '\\')) => string.push('\\'),
+                Some((_, 'n')) => string.push('\n'),
+                Some((_, 't')) => string.push('\t'),
+                Some((_, 'r')) => string.push('\r'),
+                Some((_, _)) if options.strict => {
+                    return Err(nom::Err::Failure(ParseError::new(
+                        &input[i..],
+                        "a known escape sequence",
+                    )));
+                }
+                Some((_, other)) => {
+                    string.push('\\');
+                    string.push(other);
+                }
+                None => break,
+            },
+            c => string.push(c),
+        }
+    }
+
+    Err(nom::Err::Failure(ParseError::new(
+        input,
+        "`\"` to close the string",
+    )))
+}
+
+fn parse_array<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+    depth: usize,
+) -> ParseResult<'a, KV3Value> {
     info!("Parsing array...");
 
-    // Parse elements as KV3 values, separated by commas
-    let parse_elements = separated_list0(ws(tag(",")), ws(parse_value));
-    let mut array_parser = delimited(
-        ws(tag("[")), // Opening bracket
-        map(
-            // Wrap parsed elements in a vector
-            pair(parse_elements, opt(ws(tag(",")))), // Allow optional trailing comma
-            |(elements, _)| elements,                // Discard the optional trailing comma
-        ),
-        ws(tag("]")), // Closing bracket
-    );
+    let array_parser = |input| {
+        // Opening bracket
+        let (open, _) = skip_comments_and_whitespace(input, options)?;
+        let (remaining, _) = ws(options, tag("["))(open)?;
+        let (_, depth) = nested(open, options, depth)?;
+        // Parse elements as KV3 values, separated by commas
+        let (remaining, elements) = separated_list0(
+            ws(options, tag(",")),
+            ws(options, |input| parse_value(input, options, depth)),
+        )(remaining)?;
+        // Allow optional trailing comma
+        let (remaining, comma) = opt(ws(options, tag(",")))(remaining)?;
+        let expected = if elements.is_empty() || comma.is_some() {
+            "a value or `]`"
+        } else {
+            "`,` or `]`"
+        };
+        // Closing bracket
+        let (remaining, _) = ws(options, expect(expected, tag("]")))(remaining)?;
+        Ok((remaining, elements))
+    };
 
-    let result = array_parser(input);
+    let result: ParseResult<'_, Vec<KV3Value>> = array_parser(input);
 
     // Log results or errors
     match &result {
@@ -323,26 +1515,48 @@ fn parse_array(input: &str) -> IResult<&str, KV3Value> {
     result.map(|(remaining, elements)| (remaining, KV3Value::Array(elements)))
 }
 
-fn parse_hex_array(input: &str) -> IResult<&str, KV3Value> {
+fn parse_hex_array<'a>(input: &'a str, options: &ParseOptions) -> ParseResult<'a, KV3Value> {
     info!("Parsing hex array...");
-    let result = delimited(
+    let (remaining, content) = delimited(
         tag("#["),
-        map(take_until("]"), |content: &str| {
-            content
-                .split_whitespace() // Split into hex pairs
-                .filter_map(|hex| u8::from_str_radix(hex, 16).ok()) // Parse as u8
-                .collect::<Vec<u8>>() // Collect into a vector
-        }),
+        expect("`]` to close the hex array", take_until("]")),
         tag("]"),
-    )(input);
+    )(input)?;
 
-    result.map(|(remaining, bytes)| (remaining, KV3Value::HexArray(bytes)))
+    let mut bytes = Vec::new();
+    // Split into hex pairs
+    for hex in content.split_whitespace() {
+        let is_byte = hex.len() == 2 && hex.bytes().all(|b| b.is_ascii_hexdigit());
+        if options.strict && !is_byte {
+            let offset = hex.as_ptr() as usize - input.as_ptr() as usize;
+            return Err(nom::Err::Failure(ParseError::new(
+                &input[offset..],
+                "a two-digit hex byte",
+            )));
+        }
+        // Entries that are not bytes are skipped when parsing leniently
+        bytes.extend(u8::from_str_radix(hex, 16).ok());
+    }
+
+    Ok((remaining, KV3Value::HexArray(bytes)))
 }
 
-fn parse_object(input: &str) -> IResult<&str, KV3Value> {
+fn parse_object<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+    depth: usize,
+) -> ParseResult<'a, KV3Value> {
     info!("Parsing object...");
-    let parse_fields = many0(ws(parse_key_value));
-    let result = delimited(ws(tag("{")), parse_fields, ws(tag("}")))(input);
+    let object_parser = |input| {
+        let (open, _) = skip_comments_and_whitespace(input, options)?;
+        let (remaining, _) = ws(options, tag("{"))(open)?;
+        let (_, depth) = nested(open, options, depth)?;
+        let (remaining, object) = parse_fields(remaining, options, depth)?;
+        let (remaining, _) = ws(options, expect("a key or `}`", tag("}")))(remaining)?;
+        Ok((remaining, object))
+    };
+
+    let result: ParseResult<'_, KV3Object> = object_parser(input);
 
     match &result {
         Ok((remaining, key)) => {
@@ -357,12 +1571,5 @@ fn parse_object(input: &str) -> IResult<&str, KV3Value> {
         }
     }
 
-    result.map(|(remaining, fields)| {
-        (
-            remaining,
-            KV3Value::Object(KV3Object {
-                fields: fields.into_iter().collect(),
-            }),
-        )
-    })
+    result.map(|(remaining, object)| (remaining, KV3Value::Object(object)))
 }
@@ -0,0 +1,98 @@
+/// Builds a [`KV3Value`](crate::KV3Value) from KV3-like syntax.
+///
+/// Objects are written as `{ key: value, ... }` with identifier keys, string
+/// literal keys such as `"m_Data.m_nCount"` or computed keys in parentheses.
+/// Arrays are written as `[ ... ]` and hex arrays as `#[ 0xFF, 0x00 ]`.
+/// `null`, `true` and `false` are literals; any other value is an expression
+/// converted with [`From`], e.g. numbers, strings and other `KV3Value`s.
+///
+/// ```rust
+/// use kv3::{kv3, to_kv3_string, KV3Flags, KV3Value};
+///
+/// let count = 16;
+/// let value = kv3!({
+///     m_nFlags: 5,
+///     m_Names: ["a", "b"],
+///     blob: #[0xFF, 0x00],
+///     "m_Data.m_nCount": count * 2,
+///     m_hModel: KV3Value::Flagged {
+///         flags: KV3Flags::RESOURCE,
+///         value: Box::new(kv3!("models/foo.vmdl")),
+///     },
+///     m_Child: { m_bEnabled: true, m_Parent: null },
+/// });
+///
+/// assert_eq!(value["m_Names"][1].as_str(), Some("b"));
+/// assert_eq!(value["m_Data.m_nCount"].as_i64(), Some(32));
+/// assert!(to_kv3_string(&value).unwrap().contains("blob = \n\t#[\n\t\tFF 00\n\t]"));
+/// ```
+#[macro_export]
+macro_rules! kv3 {
+    (null) => {
+        $crate::KV3Value::Null
+    };
+    (true) => {
+        $crate::KV3Value::Bool(true)
+    };
+    (false) => {
+        $crate::KV3Value::Bool(false)
+    };
+    (#[ $($byte:expr),* $(,)? ]) => {
+        $crate::KV3Value::HexArray(::std::vec![$($byte),*])
+    };
+    ([ $($tt:tt)* ]) => {
+        $crate::KV3Value::Array($crate::kv3!(@array [] $($tt)*))
+    };
+    ({}) => {
+        $crate::KV3Value::Object($crate::KV3Object::default())
+    };
+    ({ $($tt:tt)+ }) => {{
+        let mut object = $crate::KV3Object::default();
+        $crate::kv3!(@object object $($tt)*);
+        $crate::KV3Value::Object(object)
+    }};
+
+    // Array items are collected one at a time. Values that are a single
+    // token tree or a hex array are matched whole and anything else is
+    // parsed as an expression, so each item is one level of recursion.
+    (@array [$($items:expr,)*]) => {
+        ::std::vec![$($items,)*]
+    };
+    (@array [$($items:expr,)*] $value:tt $(, $($rest:tt)*)?) => {
+        $crate::kv3!(@array [$($items,)* $crate::kv3!($value),] $($($rest)*)?)
+    };
+    (@array [$($items:expr,)*] # $bytes:tt $(, $($rest:tt)*)?) => {
+        $crate::kv3!(@array [$($items,)* $crate::kv3!(# $bytes),] $($($rest)*)?)
+    };
+    (@array [$($items:expr,)*] $value:expr $(, $($rest:tt)*)?) => {
+        $crate::kv3!(@array [$($items,)* $crate::KV3Value::from($value),] $($($rest)*)?)
+    };
+
+    // Object entries are a key, `:` and a value matched like array items
+    (@object $object:ident) => {};
+    (@object $object:ident $key:tt : $value:tt $(, $($rest:tt)*)?) => {
+        $object.insert($crate::kv3!(@key $key), $crate::kv3!($value));
+        $crate::kv3!(@object $object $($($rest)*)?);
+    };
+    (@object $object:ident $key:tt : # $bytes:tt $(, $($rest:tt)*)?) => {
+        $object.insert($crate::kv3!(@key $key), $crate::kv3!(# $bytes));
+        $crate::kv3!(@object $object $($($rest)*)?);
+    };
+    (@object $object:ident $key:tt : $value:expr $(, $($rest:tt)*)?) => {
+        $object.insert($crate::kv3!(@key $key), $crate::KV3Value::from($value));
+        $crate::kv3!(@object $object $($($rest)*)?);
+    };
+    (@key $key:ident) => {
+        stringify!($key)
+    };
+    (@key $key:literal) => {
+        $key
+    };
+    (@key ($key:expr)) => {
+        $key
+    };
+
+    ($other:expr) => {
+        $crate::KV3Value::from($other)
+    };
+}
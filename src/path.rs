@@ -0,0 +1,210 @@
+//! Paths into KV3 trees, e.g. `m_Children[3].m_hModel`, and lookups of
+//! values by path.
+
+use crate::writer::escape;
+use crate::{is_key_char, parse_escaped, Error, KV3Value, ParseOptions};
+
+/// One step of a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Segment {
+    /// A key of an object.
+    Key(String),
+    /// An index into an array.
+    Index(usize),
+}
+
+/// Parses a path of keys separated by `.` and array indices in brackets,
+/// e.g. `m_Children[3].m_hModel`. Keys containing `.`, `[` or `]` are
+/// quoted in brackets, e.g. `m_Data["m_Key.x"]`. The empty path is the root.
+pub(crate) fn parse_path(path: &str) -> Result<Vec<Segment>, Error> {
+    let mut segments = Vec::new();
+    let mut rest = path;
+
+    while !rest.is_empty() {
+        if let Some(inner) = rest.strip_prefix('[') {
+            if let Some(quoted) = inner.strip_prefix('"') {
+                let (after, key) = parse_escaped(quoted, &ParseOptions::default())
+                    .map_err(|_| Error::path(path, "expected `\"` to close the key"))?;
+                rest = after[1..]
+                    .strip_prefix(']')
+                    .ok_or_else(|| Error::path(path, "expected `]` after the quoted key"))?;
+                segments.push(Segment::Key(key));
+            } else {
+                let end = inner
+                    .find(']')
+                    .ok_or_else(|| Error::path(path, "expected `]` to close the index"))?;
+                let index = inner[..end]
+                    .trim()
+                    .parse()
+                    .map_err(|_| Error::path(path, "expected an array index"))?;
+                segments.push(Segment::Index(index));
+                rest = &inner[end + 1..];
+            }
+        } else {
+            // Keys after the first one follow a `.`
+            let key = if segments.is_empty() {
+                rest
+            } else {
+                rest.strip_prefix('.')
+                    .ok_or_else(|| Error::path(path, "expected `.` or `[`"))?
+            };
+            let end = key.find(['.', '[']).unwrap_or(key.len());
+            if end == 0 {
+                return Err(Error::path(path, "expected a key"));
+            }
+            segments.push(Segment::Key(key[..end].to_string()));
+            rest = &key[end..];
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Appends `key` to a path, quoting it if needed.
+pub(crate) fn push_key(path: &mut String, key: &str) {
+    if !key.is_empty() && key.chars().all(|c| c != '.' && is_key_char(c)) {
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(key);
+    } else {
+        path.push_str("[\"");
+        path.push_str(&escape(key));
+        path.push_str("\"]");
+    }
+}
+
+pub(crate) fn push_index(path: &mut String, index: usize) {
+    path.push('[');
+    path.push_str(&index.to_string());
+    path.push(']');
+}
+
+/// Lookups by path, using the syntax of [`Document`](crate::document::Document)
+/// or a JSON Pointer. Like the other accessors they see through flags and
+/// kept number text.
+///
+/// ```rust
+/// use kv3::{parse_kv3_with, KV3Value};
+///
+/// let input = r#"{ m_Children = [ { m_hModel = resource:"models/foo.vmdl" } ] }"#;
+/// let (_, mut root) = parse_kv3_with(input, &Default::default()).unwrap();
+///
+/// let model = root.get_path("m_Children[0].m_hModel").and_then(KV3Value::as_str);
+/// assert_eq!(model, Some("models/foo.vmdl"));
+/// assert_eq!(root.pointer("/m_Children/0/m_hModel").and_then(KV3Value::as_str), model);
+///
+/// root.set_path("m_Children[0].m_nSkin", KV3Value::Int(2)).unwrap();
+/// assert_eq!(root["m_Children"][0]["m_nSkin"].as_i64(), Some(2));
+/// ```
+impl KV3Value {
+    /// Returns the value at a path such as `m_Children[3].m_hModel`, or
+    /// `None` if the path is malformed or leads nowhere. The empty path is
+    /// the value itself.
+    pub fn get_path(&self, path: &str) -> Option<&KV3Value> {
+        parse_path(path)
+            .ok()?
+            .iter()
+            .try_fold(self, |value, segment| value.child(segment))
+    }
+
+    /// Returns the value at `path` mutably, see [`KV3Value::get_path`].
+    pub fn get_path_mut(&mut self, path: &str) -> Option<&mut KV3Value> {
+        parse_path(path)
+            .ok()?
+            .iter()
+            .try_fold(self, |value, segment| value.child_mut(segment))
+    }
+
+    /// Sets the value at `path`. An existing value is replaced; a missing
+    /// key is added to its object.
+    ///
+    /// Fails if the path is malformed or empty, its parent is not an object
+    /// or array, or it points past the end of an array.
+    pub fn set_path(&mut self, path: &str, value: KV3Value) -> Result<(), Error> {
+        let segments = parse_path(path)?;
+        let (last, parent) = segments
+            .split_last()
+            .ok_or_else(|| Error::path(path, "the root cannot be replaced"))?;
+        let parent = parent
+            .iter()
+            .try_fold(self, |value, segment| value.child_mut(segment))
+            .ok_or_else(|| Error::path(path, "the parent does not exist"))?;
+
+        match last {
+            Segment::Key(key) => {
+                let obj = parent
+                    .as_object_mut()
+                    .ok_or_else(|| Error::path(path, "the parent is not an object"))?;
+                obj.insert(key.clone(), value);
+            }
+            Segment::Index(index) => {
+                let item = parent
+                    .as_array_mut()
+                    .ok_or_else(|| Error::path(path, "the parent is not an array"))?
+                    .get_mut(*index)
+                    .ok_or_else(|| Error::path(path, "the array index is out of range"))?;
+                *item = value;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value at a JSON Pointer (RFC 6901) such as
+    /// `/m_Children/3/m_hModel`, where `~1` stands for `/` and `~0` for `~`
+    /// in keys. The empty pointer is the value itself.
+    pub fn pointer(&self, pointer: &str) -> Option<&KV3Value> {
+        pointer_tokens(pointer)?.try_fold(self, |value, token| match value.as_array() {
+            Some(items) => items.get(pointer_index(&token)?),
+            None => value.as_object()?.get(&token),
+        })
+    }
+
+    /// Returns the value at a JSON Pointer mutably, see [`KV3Value::pointer`].
+    pub fn pointer_mut(&mut self, pointer: &str) -> Option<&mut KV3Value> {
+        pointer_tokens(pointer)?.try_fold(self, |value, token| {
+            if value.as_array().is_some() {
+                value.as_array_mut()?.get_mut(pointer_index(&token)?)
+            } else {
+                value.as_object_mut()?.get_mut(&token)
+            }
+        })
+    }
+
+    pub(crate) fn child(&self, segment: &Segment) -> Option<&KV3Value> {
+        match segment {
+            Segment::Key(key) => self.as_object()?.get(key),
+            Segment::Index(index) => self.as_array()?.get(*index),
+        }
+    }
+
+    fn child_mut(&mut self, segment: &Segment) -> Option<&mut KV3Value> {
+        match segment {
+            Segment::Key(key) => self.as_object_mut()?.get_mut(key),
+            Segment::Index(index) => self.as_array_mut()?.get_mut(*index),
+        }
+    }
+}
+
+/// Splits a JSON Pointer into its unescaped reference tokens.
+fn pointer_tokens(pointer: &str) -> Option<impl Iterator<Item = String> + '_> {
+    let tokens = match pointer {
+        "" => None,
+        _ => Some(pointer.strip_prefix('/')?.split('/')),
+    };
+    Some(
+        tokens
+            .into_iter()
+            .flatten()
+            .map(|token| token.replace("~1", "/").replace("~0", "~")),
+    )
+}
+
+/// Array indices in JSON Pointers are plain decimal numbers without leading
+/// zeros.
+fn pointer_index(token: &str) -> Option<usize> {
+    if token.starts_with('+') || (token.len() > 1 && token.starts_with('0')) {
+        return None;
+    }
+    token.parse().ok()
+}
@@ -0,0 +1,437 @@
+//! Selecting many values at once with a JSONPath-like query.
+
+use std::cmp::Ordering;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_until, take_while1},
+    character::complete::{char, digit1, multispace0},
+    combinator::{all_consuming, map, map_opt, opt, recognize},
+    multi::many0,
+    number::complete::double,
+    sequence::{delimited, pair, preceded, terminated},
+};
+
+use crate::error::{finish, ParseError, ParseResult};
+use crate::path::{push_index, push_key, Segment};
+use crate::{expect, parse_escaped, Error, KV3Value, ParseOptions};
+
+/// A value found by [`select`], with its path in the syntax of
+/// [`KV3Value::get_path`].
+#[derive(Debug, Clone)]
+pub struct Selected<'a> {
+    /// Path from the queried value, e.g. `m_Children[3].m_hModel`.
+    pub path: String,
+    pub value: &'a KV3Value,
+}
+
+/// Returns every value in `value` matched by a JSONPath-like `query`, in
+/// document order.
+///
+/// Queries start with `$`, the queried value, followed by:
+///
+/// - `.key` or `["key"]`: the value of a key
+/// - `[3]`, `[-1]`: an array item, counting from the end if negative
+/// - `[1:5]`, `[::2]`: a slice of an array, with an optional step
+/// - `.*` or `[*]`: every item or value
+/// - `..`: the following selector applied to all descendants, e.g.
+///   `$..m_hMaterial` or `$..*`
+/// - `[?@.m_flRadius > 10]`: every item or value for which a path below `@`
+///   exists or compares `==`, `!=`, `<`, `<=`, `>` or `>=` to a number,
+///   string, `true`, `false` or `null`
+///
+/// Like the other accessors, the query sees through flags and kept number
+/// text. Keys repeated under
+/// [`DuplicateKeys::PreserveAll`](crate::DuplicateKeys::PreserveAll) select
+/// only their first value with `.key` and `["key"]`, as with
+/// [`KV3Object::get`](crate::KV3Object::get) and paths, while `*`, `..` and
+/// filters visit every repeat. A malformed query fails with
+/// [`Error::Syntax`].
+///
+/// ```rust
+/// use kv3::{parse_kv3_with, select};
+///
+/// let input = r#"{
+///     m_Spheres = [
+///         { m_flRadius = 4.0 m_hMaterial = resource:"materials/a.vmat" },
+///         { m_flRadius = 16.0 m_hMaterial = resource:"materials/b.vmat" },
+///     ]
+/// }"#;
+/// let (_, root) = parse_kv3_with(input, &Default::default()).unwrap();
+///
+/// let materials = select(&root, "$..m_hMaterial").unwrap();
+/// assert_eq!(materials[1].path, "m_Spheres[1].m_hMaterial");
+/// assert_eq!(materials[1].value.as_str(), Some("materials/b.vmat"));
+///
+/// let large = select(&root, "$.m_Spheres[?@.m_flRadius > 10]").unwrap();
+/// assert_eq!(large.len(), 1);
+/// ```
+pub fn select<'a>(value: &'a KV3Value, query: &str) -> Result<Vec<Selected<'a>>, Error> {
+    let (_, steps) = finish(query, all_consuming(parse_query)(query))?;
+
+    let mut selected = vec![Selected {
+        path: String::new(),
+        value,
+    }];
+    for step in &steps {
+        let mut next = Vec::new();
+        for node in &selected {
+            match step {
+                Step::Child(selector) => selector.apply(node, &mut next),
+                Step::Descendants(selector) => descend(node, selector, &mut next),
+            }
+        }
+        selected = next;
+    }
+    Ok(selected)
+}
+
+enum Step {
+    Child(Selector),
+    /// The selector applied to a value and all of its descendants, `..`.
+    Descendants(Selector),
+}
+
+enum Selector {
+    Key(String),
+    Wildcard,
+    Index(i64),
+    Slice {
+        start: Option<i64>,
+        end: Option<i64>,
+        step: Option<i64>,
+    },
+    Filter(Filter),
+}
+
+struct Filter {
+    /// Path below `@`.
+    path: Vec<Segment>,
+    comparison: Option<(Comparison, Literal)>,
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+enum Literal {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Null,
+}
+
+impl Selector {
+    fn apply<'a>(&self, node: &Selected<'a>, out: &mut Vec<Selected<'a>>) {
+        match self {
+            // Like `get`, only the first value of a repeated key
+            Selector::Key(key) => {
+                if let Some(value) = node.value.as_object().and_then(|obj| obj.get(key)) {
+                    out.push(child_key(node, key, value));
+                }
+            }
+            Selector::Wildcard => children(node, out),
+            Selector::Index(index) => {
+                let Some(items) = node.value.as_array() else {
+                    return;
+                };
+                // Negative indices count from the end
+                let index = if *index < 0 {
+                    items.len().checked_sub(index.unsigned_abs() as usize)
+                } else {
+                    usize::try_from(*index).ok()
+                };
+                if let Some((index, value)) = index.and_then(|i| Some((i, items.get(i)?))) {
+                    out.push(child_index(node, index, value));
+                }
+            }
+            Selector::Slice { start, end, step } => {
+                let Some(items) = node.value.as_array() else {
+                    return;
+                };
+                for i in slice_indices(items.len(), *start, *end, step.unwrap_or(1)) {
+                    out.push(child_index(node, i, &items[i]));
+                }
+            }
+            Selector::Filter(filter) => {
+                let mut candidates = Vec::new();
+                children(node, &mut candidates);
+                out.extend(
+                    candidates
+                        .into_iter()
+                        .filter(|candidate| filter.matches(candidate.value)),
+                );
+            }
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, value: &KV3Value) -> bool {
+        let target = self
+            .path
+            .iter()
+            .try_fold(value, |value, segment| value.child(segment));
+
+        let Some((comparison, literal)) = &self.comparison else {
+            return target.is_some();
+        };
+        let ordering = target.and_then(|target| match literal {
+            Literal::Number(n) => target.as_f64()?.partial_cmp(n),
+            Literal::String(s) => Some(target.as_str()?.cmp(s.as_str())),
+            Literal::Bool(b) => Some(target.as_bool()?.cmp(b)),
+            Literal::Null => target.is_null().then_some(Ordering::Equal),
+        });
+
+        match comparison {
+            Comparison::Eq => ordering == Some(Ordering::Equal),
+            Comparison::Ne => ordering != Some(Ordering::Equal),
+            Comparison::Lt => ordering == Some(Ordering::Less),
+            Comparison::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Comparison::Gt => ordering == Some(Ordering::Greater),
+            Comparison::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+fn child_key<'a>(node: &Selected<'a>, key: &str, value: &'a KV3Value) -> Selected<'a> {
+    let mut path = node.path.clone();
+    push_key(&mut path, key);
+    Selected { path, value }
+}
+
+fn child_index<'a>(node: &Selected<'a>, index: usize, value: &'a KV3Value) -> Selected<'a> {
+    let mut path = node.path.clone();
+    push_index(&mut path, index);
+    Selected { path, value }
+}
+
+/// Pushes the values of an object or the items of an array.
+fn children<'a>(node: &Selected<'a>, out: &mut Vec<Selected<'a>>) {
+    if let Some(obj) = node.value.as_object() {
+        out.extend(obj.iter().map(|(key, value)| child_key(node, key, value)));
+    } else if let Some(items) = node.value.as_array() {
+        out.extend(
+            items
+                .iter()
+                .enumerate()
+                .map(|(i, value)| child_index(node, i, value)),
+        );
+    }
+}
+
+/// Applies `selector` to `node` and then to each of its descendants.
+fn descend<'a>(node: &Selected<'a>, selector: &Selector, out: &mut Vec<Selected<'a>>) {
+    selector.apply(node, out);
+    let mut nodes = Vec::new();
+    children(node, &mut nodes);
+    for child in &nodes {
+        descend(child, selector, out);
+    }
+}
+
+/// Indices of a Python-style slice of an array of length `len`.
+fn slice_indices(
+    len: usize,
+    start: Option<i64>,
+    end: Option<i64>,
+    step: i64,
+) -> impl Iterator<Item = usize> {
+    let len = len as i64;
+    // Negative bounds count from the end
+    let bound = |i: i64, low: i64, high: i64| (if i < 0 { i + len } else { i }).clamp(low, high);
+
+    let (start, end) = if step > 0 {
+        (
+            start.map_or(0, |i| bound(i, 0, len)),
+            end.map_or(len, |i| bound(i, 0, len)),
+        )
+    } else {
+        (
+            start.map_or(len - 1, |i| bound(i, -1, len - 1)),
+            end.map_or(-1, |i| bound(i, -1, len - 1)),
+        )
+    };
+
+    let mut i = start;
+    std::iter::from_fn(move || {
+        let in_range = match step.cmp(&0) {
+            Ordering::Greater => i < end,
+            Ordering::Less => i > end,
+            Ordering::Equal => false,
+        };
+        if !in_range {
+            return None;
+        }
+        let index = i as usize;
+        i += step;
+        Some(index)
+    })
+}
+
+fn parse_query(input: &str) -> ParseResult<'_, Vec<Step>> {
+    preceded(
+        expect("`$` at the start of the query", |input| char('$')(input)),
+        many0(parse_step),
+    )(input)
+}
+
+fn parse_step(input: &str) -> ParseResult<'_, Step> {
+    alt((
+        map(
+            preceded(
+                tag(".."),
+                expect("a key, `*` or `[` after `..`", |input| {
+                    alt((parse_bracket, parse_dot_selector))(input)
+                }),
+            ),
+            Step::Descendants,
+        ),
+        map(
+            preceded(
+                char('.'),
+                expect("a key or `*` after `.`", parse_dot_selector),
+            ),
+            Step::Child,
+        ),
+        map(parse_bracket, Step::Child),
+    ))(input)
+}
+
+fn parse_dot_selector(input: &str) -> ParseResult<'_, Selector> {
+    alt((
+        map(char('*'), |_| Selector::Wildcard),
+        map(parse_name, Selector::Key),
+    ))(input)
+}
+
+/// Keys after `.`, which cannot contain `.` themselves.
+fn parse_name(input: &str) -> ParseResult<'_, String> {
+    map(
+        take_while1(|c: char| c.is_alphanumeric() || matches!(c, '_' | ':')),
+        str::to_string,
+    )(input)
+}
+
+fn parse_bracket(input: &str) -> ParseResult<'_, Selector> {
+    delimited(
+        terminated(char('['), multispace0),
+        expect("a key, index, slice, `*` or filter", |input| {
+            alt((
+                map(parse_quoted, Selector::Key),
+                map(char('*'), |_| Selector::Wildcard),
+                map(parse_filter, Selector::Filter),
+                parse_index_or_slice,
+            ))(input)
+        }),
+        preceded(multispace0, expect("`]`", |input| char(']')(input))),
+    )(input)
+}
+
+/// A key in single or double quotes.
+fn parse_quoted(input: &str) -> ParseResult<'_, String> {
+    alt((
+        delimited(
+            char('"'),
+            |input| parse_escaped(input, &ParseOptions::default()),
+            char('"'),
+        ),
+        map(
+            delimited(
+                char('\''),
+                expect("`'` to close the key", |input| take_until("'")(input)),
+                char('\''),
+            ),
+            str::to_string,
+        ),
+    ))(input)
+}
+
+fn parse_integer(input: &str) -> ParseResult<'_, i64> {
+    map_opt(recognize(pair(opt(char('-')), digit1)), |digits: &str| {
+        digits.parse().ok()
+    })(input)
+}
+
+fn parse_index_or_slice(input: &str) -> ParseResult<'_, Selector> {
+    let (rest, start) = opt(parse_integer)(input)?;
+    let Ok((rest, _)) = char::<_, ParseError>(':')(rest) else {
+        return match start {
+            Some(index) => Ok((rest, Selector::Index(index))),
+            None => Err(nom::Err::Error(ParseError::new(input, "an index"))),
+        };
+    };
+
+    let (rest, end) = opt(parse_integer)(rest)?;
+    let (rest, step) = opt(preceded(char(':'), opt(parse_integer)))(rest)?;
+    let step = step.flatten();
+    Ok((rest, Selector::Slice { start, end, step }))
+}
+
+/// `?@.path`, `?@.path > 10`, optionally in parentheses.
+fn parse_filter(input: &str) -> ParseResult<'_, Filter> {
+    preceded(
+        char('?'),
+        expect("a filter such as `@.m_flRadius > 10`", |input| {
+            alt((
+                delimited(
+                    terminated(char('('), multispace0),
+                    parse_filter_expression,
+                    preceded(multispace0, char(')')),
+                ),
+                parse_filter_expression,
+            ))(input)
+        }),
+    )(input)
+}
+
+fn parse_filter_expression(input: &str) -> ParseResult<'_, Filter> {
+    let segment = alt((
+        map(preceded(char('.'), parse_name), Segment::Key),
+        delimited(
+            char('['),
+            alt((
+                map(parse_quoted, Segment::Key),
+                map_opt(digit1, |digits: &str| {
+                    digits.parse().ok().map(Segment::Index)
+                }),
+            )),
+            char(']'),
+        ),
+    ));
+    let comparison = alt((
+        map(tag("=="), |_| Comparison::Eq),
+        map(tag("!="), |_| Comparison::Ne),
+        map(tag("<="), |_| Comparison::Le),
+        map(tag(">="), |_| Comparison::Ge),
+        map(tag("<"), |_| Comparison::Lt),
+        map(tag(">"), |_| Comparison::Gt),
+    ));
+
+    let (input, _) = char('@')(input)?;
+    let (input, path) = many0(segment)(input)?;
+    let (input, comparison) = opt(pair(
+        preceded(multispace0, comparison),
+        preceded(
+            multispace0,
+            expect("a number, string, `true`, `false` or `null`", parse_literal),
+        ),
+    ))(input)?;
+    Ok((input, Filter { path, comparison }))
+}
+
+fn parse_literal(input: &str) -> ParseResult<'_, Literal> {
+    alt((
+        map(parse_quoted, Literal::String),
+        map(tag("true"), |_| Literal::Bool(true)),
+        map(tag("false"), |_| Literal::Bool(false)),
+        map(tag("null"), |_| Literal::Null),
+        map(double, Literal::Number),
+    ))(input)
+}
@@ -4,7 +4,8 @@ mod tests {
     use log::{error, info};
     use serde::{Deserialize, Serialize};
 
-    use crate::kv3_serde::serde_kv3;
+    use crate::kv3_serde::{serde_kv3, to_string};
+    use crate::{parse_kv3_document, to_kv3_string, Error, KV3Flags, KV3Header, KV3Value};
 
     #[derive(Deserialize, Serialize)]
     struct TestNestedObj {
@@ -347,5 +348,2201 @@ mod tests {
                 panic!("expected to pass the test {:?}", e);
             }
         }
+
+        let phys = serde_kv3::<WorldPhys>(input).expect("expected to parse");
+        assert_eq!(phys.flags, 0);
+    }
+
+    #[test]
+    fn kv3_parse_document_header() {
+        let input = r#"
+<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:vpcf36:version{d47d07e6-072c-49cb-9718-5bfd8d6c3d21} -->
+{
+    m_nMaxParticles = 16
+}
+"#;
+
+        let (header, root) = parse_kv3_document(input).expect("expected to parse document");
+        assert_eq!(
+            header,
+            KV3Header {
+                encoding: "text".to_string(),
+                encoding_guid: "e21c7f3c-8a33-41c5-9977-a76d3a32aa0d".to_string(),
+                format: "vpcf36".to_string(),
+                format_guid: "d47d07e6-072c-49cb-9718-5bfd8d6c3d21".to_string(),
+            }
+        );
+        assert!(matches!(root, KV3Value::Object(_)));
+        assert_eq!(
+            input.trim_start().lines().next().unwrap(),
+            header.to_string()
+        );
+    }
+
+    #[test]
+    fn kv3_parse_document_missing_header() {
+        let input = r#"
+// not a header
+{
+    m_nMaxParticles = 16
+}
+"#;
+
+        assert!(parse_kv3_document(input).is_err());
+    }
+
+    #[test]
+    fn kv3_parse_document_trailing_input() {
+        let header = KV3Header::default().to_string();
+
+        let trailing_comments = format!("{}\n{{ a = 1 }}\n/* done */\n// end", header);
+        assert!(parse_kv3_document(&trailing_comments).is_ok());
+        assert!(crate::kv3_serde::from_str::<KV3Value>(&trailing_comments).is_ok());
+
+        for trailing in ["}", "{ b = 2 }", "garbage"] {
+            let input = format!("{}\n{{ a = 1 }}\n{}\n", header, trailing);
+            match parse_kv3_document(&input) {
+                Err(Error::Syntax { line, expected, .. }) => {
+                    assert_eq!(line, 3, "{:?}", input);
+                    assert_eq!(expected, "the end of the input");
+                }
+                other => panic!("expected a syntax error for {:?}, got {:?}", input, other),
+            }
+            assert!(serde_kv3::<KV3Value>(&input).is_err());
+        }
+
+        // `parse_kv3` stops after the root object and returns the rest
+        let (remaining, _) = crate::parse_kv3("{ a = 1 } { b = 2 }").expect("expected to parse");
+        assert_eq!(remaining, "{ b = 2 }");
+    }
+
+    #[test]
+    fn kv3_parse_error_location() {
+        let input = r#"
+{
+    m_nMaxParticles = 16
+    m_nFlags 5
+}
+"#;
+
+        match crate::parse_kv3(input) {
+            Err(Error::Syntax {
+                line,
+                column,
+                snippet,
+                expected,
+            }) => {
+                assert_eq!((line, column), (4, 14));
+                assert_eq!(snippet, "m_nFlags 5");
+                assert_eq!(expected, "`=` after key `m_nFlags`");
+            }
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+
+        let err = serde_kv3::<ArrayTest2>(input).expect_err("expected a syntax error");
+        assert_eq!(
+            err.to_string(),
+            "expected `=` after key `m_nFlags` at line 4, column 14: `m_nFlags 5`"
+        );
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct UnsignedTest {
+        #[serde(rename = "m_bonesHash")]
+        bones_hash: Vec<u64>,
+        count: u64,
+    }
+
+    #[test]
+    fn kv3_parse_unsigned_integers() {
+        let input = r#"
+{
+    m_bonesHash = [ 18446744073709551615, 9223372036854775808, 42 ]
+    count = 7
+}
+"#;
+        let (_, fields) = crate::parse_kv3(input).expect("expected to parse");
+        match fields.get("m_bonesHash") {
+            Some(KV3Value::Array(items)) => {
+                assert!(matches!(items[0], KV3Value::UInt(u64::MAX)));
+                assert!(matches!(items[1], KV3Value::UInt(9223372036854775808)));
+                // Values in the i64 range stay signed
+                assert!(matches!(items[2], KV3Value::Int(42)));
+            }
+            other => panic!("expected an array, got {:?}", other),
+        }
+
+        let parsed: UnsignedTest = serde_kv3(input).expect("expected to deserialize");
+        assert_eq!(parsed.bones_hash, [u64::MAX, 9223372036854775808, 42]);
+        assert_eq!(parsed.count, 7);
+
+        let written = to_string(&parsed).expect("expected to serialize");
+        assert!(written.contains("m_bonesHash = [ 18446744073709551615, 9223372036854775808, 42 ]"));
+
+        // Negative values and values past u64::MAX do not fit any integer
+        assert!(crate::parse_kv3("{ a = 18446744073709551616 }").is_err());
+        assert!(crate::parse_kv3("{ a = -9223372036854775809 }").is_err());
+        assert!(serde_kv3::<UnsignedTest>("{ m_bonesHash = [ -1 ] count = 0 }").is_err());
+
+        // Binary KV3 keeps its unsigned types
+        for value in [KV3Value::UInt(5), KV3Value::UInt(u64::MAX)] {
+            let data = crate::binary::to_vec(&value).expect("expected to write binary KV3");
+            let read = crate::binary::from_slice(&data).expect("expected to read binary KV3");
+            assert!(matches!((value, read), (KV3Value::UInt(a), KV3Value::UInt(b)) if a == b));
+        }
+    }
+
+    #[test]
+    fn kv3_parse_hex_and_non_finite_numbers() {
+        let input = r#"
+{
+    m_nMask = 0x1F
+    m_nNegative = -0x10
+    m_nHash = 0XFFFFFFFFFFFFFFFF
+    m_flInf = inf
+    m_flNegInf = -inf
+    m_flNaN = nan
+    m_Values = [ Infinity, -INF, NaN ]
+}
+"#;
+        let (_, fields) = crate::parse_kv3(input).expect("expected to parse");
+        assert!(matches!(fields.get("m_nMask"), Some(KV3Value::Int(31))));
+        assert!(matches!(
+            fields.get("m_nNegative"),
+            Some(KV3Value::Int(-16))
+        ));
+        assert!(matches!(
+            fields.get("m_nHash"),
+            Some(KV3Value::UInt(u64::MAX))
+        ));
+        assert!(matches!(fields.get("m_flInf"), Some(KV3Value::Double(d)) if *d == f64::INFINITY));
+        assert!(
+            matches!(fields.get("m_flNegInf"), Some(KV3Value::Double(d)) if *d == f64::NEG_INFINITY)
+        );
+        assert!(matches!(fields.get("m_flNaN"), Some(KV3Value::Double(d)) if d.is_nan()));
+        match fields.get("m_Values") {
+            Some(KV3Value::Array(items)) => {
+                assert!(matches!(items[0], KV3Value::Double(d) if d == f64::INFINITY));
+                assert!(matches!(items[1], KV3Value::Double(d) if d == f64::NEG_INFINITY));
+                assert!(matches!(items[2], KV3Value::Double(d) if d.is_nan()));
+            }
+            other => panic!("expected an array, got {:?}", other),
+        }
+
+        // `inf` must not be the start of a longer word
+        assert!(crate::parse_kv3("{ a = info }").is_err());
+        assert!(crate::parse_kv3("{ a = 0x1FFFFFFFFFFFFFFFF }").is_err());
+        assert!(crate::parse_kv3("{ a = -0x8000000000000001 }").is_err());
+        let (_, fields) =
+            crate::parse_kv3("{ a = -0x8000000000000000 }").expect("expected to parse");
+        assert!(matches!(fields.get("a"), Some(KV3Value::Int(i64::MIN))));
+
+        // Non-finite values are written as `inf`, `-inf` and `nan`, which read back
+        let (_, root) = parse_kv3_document(&format!(
+            "{}\n{{ a = [ inf, -inf, nan ] }}",
+            KV3Header::default()
+        ))
+        .expect("expected to parse");
+        let text = to_kv3_string(&root).unwrap();
+        assert!(text.contains("a = [ inf, -inf, nan ]"));
+        assert!(parse_kv3_document(&text).is_ok());
+    }
+
+    #[derive(Deserialize)]
+    struct NumberTextTest {
+        #[serde(rename = "m_flRadius")]
+        radius: f64,
+        #[serde(rename = "m_nMask")]
+        mask: u32,
+        #[serde(rename = "m_vColor")]
+        color: Vec<f64>,
+    }
+
+    #[test]
+    fn kv3_parse_keep_number_text() {
+        use crate::{parse_kv3_with, ParseOptions};
+
+        let input = format!(
+            "{}\n{{\n\tm_flRadius = 5.000000\n\tm_nMask = 0x1F\n\tm_flScale = 1e3\n\tm_vColor = [ 1.000000, 0.500000, -0.0 ]\n\tm_hModel = resource:00005\n}}\n",
+            KV3Header::default()
+        );
+
+        // By default numbers are written in their canonical form
+        let (_, root) = parse_kv3_with(&input, &ParseOptions::new()).expect("expected to parse");
+        let written = to_kv3_string(&root).unwrap();
+        assert!(written.contains("m_flRadius = 5.0\n"));
+        assert!(written.contains("m_nMask = 31\n"));
+
+        let options = ParseOptions::new().keep_number_text(true);
+        let (_, root) = parse_kv3_with(&input, &options).expect("expected to parse");
+        let written = to_kv3_string(&root).unwrap();
+        #[cfg(feature = "preserve_order")]
+        assert_eq!(written, input);
+        for line in input.lines() {
+            assert!(written.lines().any(|l| l == line), "{}", line);
+        }
+
+        // Numbers keep their values for serde and binary KV3
+        let parsed = NumberTextTest::deserialize(root).expect("expected to deserialize");
+        assert_eq!(parsed.radius, 5.0);
+        assert_eq!(parsed.mask, 31);
+        assert_eq!(parsed.color, [1.0, 0.5, -0.0]);
+
+        let (_, root) = parse_kv3_with(&input, &options).expect("expected to parse");
+        let data = crate::binary::to_vec(&root).expect("expected to write binary KV3");
+        let decoded = crate::binary::from_slice(&data).expect("expected to read binary KV3");
+        assert!(to_kv3_string(&decoded)
+            .unwrap()
+            .contains("m_flRadius = 5.0\n"));
+
+        // A changed value no longer matches its text and is written as usual
+        let mut fields = root_fields(root);
+        match fields.get_mut("m_flRadius") {
+            Some(KV3Value::Number { value, text }) => {
+                assert_eq!(text, "5.000000");
+                **value = KV3Value::Double(2.5);
+            }
+            other => panic!("expected a number, got {:?}", other),
+        }
+        let written = to_kv3_string(&KV3Value::Object(crate::KV3Object::new(fields))).unwrap();
+        assert!(written.contains("m_flRadius = 2.5\n"));
+        assert!(written.contains("m_nMask = 0x1F\n"));
+    }
+
+    #[test]
+    fn kv3_parse_error_messages() {
+        let cases = [
+            ("m_a = 1", "`{` to open the root object"),
+            ("{\n\ta = \n}", "a value for key `a`"),
+            ("{\n\ta = \"open\n}", "`\"` to close the string"),
+            ("{\n\ta = [ 1 2 ]\n}", "`,` or `]`"),
+            ("{\n\ta = [ 1, , 2 ]\n}", "a value or `]`"),
+            ("{\n\ta = { b = 1\n}", "a key or `}`"),
+            ("{\n\ta = resource:\n}", "a value after the flags"),
+            ("{\n\ta = #[ 01 02\n}", "`]` to close the hex array"),
+            ("{\n\ta = 1\n\t/* open\n}", "`*/` to close the comment"),
+            ("{\n\tx = 0x\n}", "hex digits"),
+            ("{\n\tx = -0xZZ\n}", "hex digits"),
+        ];
+
+        for (input, expected) in cases {
+            match crate::parse_kv3(input) {
+                Err(Error::Syntax { expected: e, .. }) => assert_eq!(e, expected, "{:?}", input),
+                other => panic!("expected a syntax error for {:?}, got {:?}", input, other),
+            }
+        }
+        // Once `0x` is read the value has to be hexadecimal
+        assert_syntax_error(crate::parse_kv3("{ x = 0x }"), 1, 9, "hex digits");
+
+        // Errors from deserializing the parsed value are not syntax errors
+        let err = serde_kv3::<ArrayTest2>("{ array_ints = [ \"a\" ] }")
+            .expect_err("expected a type error");
+        assert!(matches!(err, Error::Message(_)), "{:?}", err);
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct EscapeTest {
+        quoted: String,
+        path: String,
+        control: String,
+        unknown: String,
+        backslash: String,
+        multiline: String,
+    }
+
+    #[test]
+    fn kv3_parse_string_escapes() {
+        let input = r#"
+{
+    quoted = "say \"hi\""
+    path = "C:\\Program Files\\Steam\\"
+    control = "a\tb\nc\rd"
+    unknown = "C:\Steam"
+    backslash = "\\"
+    multiline = """
+raw \"text\"
+"""
+}
+"#;
+
+        let parsed = serde_kv3::<EscapeTest>(input).expect("expected to parse escapes");
+        assert_eq!(parsed.quoted, "say \"hi\"");
+        assert_eq!(parsed.path, "C:\\Program Files\\Steam\\");
+        assert_eq!(parsed.control, "a\tb\nc\rd");
+        assert_eq!(parsed.unknown, "C:\\Steam");
+        assert_eq!(parsed.backslash, "\\");
+        // `"""` strings are raw
+        assert_eq!(parsed.multiline, "raw \\\"text\\\"");
+    }
+
+    #[test]
+    fn kv3_truncate_multibyte_strings() {
+        use crate::truncate_str;
+
+        assert_eq!(truncate_str("äöü", 3), "äöü");
+        assert_eq!(truncate_str("äöü", 2), "äö... (truncated)");
+        assert_eq!(truncate_str("a€b", 1), "a... (truncated)");
+        assert_eq!(truncate_str("", 0), "");
+    }
+
+    #[test]
+    fn kv3_write_string_escapes() {
+        let data = EscapeTest {
+            quoted: "say \"hi\"".to_string(),
+            path: "C:\\Program Files\\Steam\\".to_string(),
+            control: "tab\tcarriage\r".to_string(),
+            unknown: "ends with a line break\r\n\r".to_string(),
+            backslash: "\\\"\"\"\\".to_string(),
+            multiline: "quote \"\"\" inside\nand a break".to_string(),
+        };
+
+        let text = to_string(&data).expect("expected to serialize");
+        assert!(text.contains(r#"quoted = "say \"hi\"""#), "{}", text);
+        assert!(
+            text.contains(r#"path = "C:\\Program Files\\Steam\\""#),
+            "{}",
+            text
+        );
+        assert!(text.contains(r#"control = "tab\tcarriage\r""#), "{}", text);
+
+        let reparsed = serde_kv3::<EscapeTest>(&text).expect("expected to reparse");
+        assert_eq!(reparsed.quoted, data.quoted);
+        assert_eq!(reparsed.path, data.path);
+        assert_eq!(reparsed.control, data.control);
+        assert_eq!(reparsed.unknown, data.unknown);
+        assert_eq!(reparsed.backslash, data.backslash);
+        assert_eq!(reparsed.multiline, data.multiline);
+    }
+
+    #[test]
+    fn kv3_parse_quoted_and_dotted_keys() {
+        let input = r#"
+{
+    "my key" = 1
+    m_Data.m_nCount = 2
+    ns:m_nValue = 3
+    "say \"hi\"" = 4
+    nested = { "inner key" = 5 }
+}
+"#;
+
+        let (_, fields) = crate::parse_kv3(input).expect("expected to parse keys");
+        for (key, value) in [
+            ("my key", 1),
+            ("m_Data.m_nCount", 2),
+            ("ns:m_nValue", 3),
+            ("say \"hi\"", 4),
+        ] {
+            assert!(
+                matches!(fields.get(key), Some(KV3Value::Int(v)) if *v == value),
+                "{}",
+                key
+            );
+        }
+
+        let value = KV3Value::Object(crate::KV3Object::new(fields));
+        let text = to_kv3_string(&value).unwrap();
+        assert!(text.contains("\t\"my key\" = 1\n"), "{}", text);
+        assert!(text.contains("\tm_Data.m_nCount = 2\n"), "{}", text);
+        assert!(text.contains("\tns:m_nValue = 3\n"), "{}", text);
+        assert!(text.contains("\t\"say \\\"hi\\\"\" = 4\n"), "{}", text);
+        assert!(text.contains("\t\"inner key\" = 5\n"), "{}", text);
+
+        let (_, reparsed) = parse_kv3_document(&text).expect("expected to reparse");
+        assert_eq!(reparsed, value);
+    }
+
+    #[test]
+    fn kv3_parse_rejects_empty_keys() {
+        let input = "{\n\tm_nCount = 1\n\t= 5\n}";
+        match crate::parse_kv3(input) {
+            Err(Error::Syntax {
+                line,
+                column,
+                expected,
+                ..
+            }) => {
+                assert_eq!((line, column), (3, 2));
+                assert_eq!(expected, "a key before `=`");
+            }
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+        assert!(crate::parse_kv3("{ nested = { = 5 } }").is_err());
+
+        // A quoted empty key is rejected the same way
+        assert_syntax_error(
+            crate::parse_kv3("{\n\tm_nCount = 1\n\t\"\" = 5\n}"),
+            3,
+            2,
+            "a key before `=`",
+        );
+
+        // and is not written either
+        let mut obj = crate::KV3Object::default();
+        obj.insert("", KV3Value::Int(5));
+        assert!(to_kv3_string(&KV3Value::Object(obj)).is_err());
+        let mut doc = crate::document::Document::parse("{ a = 1 }").unwrap();
+        assert!(matches!(
+            doc.set("[\"\"]", &KV3Value::Int(5)),
+            Err(Error::Path { .. })
+        ));
+    }
+
+    /// Fields of the root object returned by `parse_kv3_with`.
+    fn root_fields(root: KV3Value) -> crate::KV3Map {
+        match root {
+            KV3Value::Object(obj) => obj.fields,
+            other => panic!("expected an object, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn kv3_parse_duplicate_keys_error() {
+        use crate::{parse_kv3_with, DuplicateKeys, ParseOptions};
+
+        let input = "{\n\tm_nCount = 1\n\tm_Child = { a = 1 }\n\tm_nCount = 2\n}";
+
+        // By default the last value wins
+        let (_, fields) = crate::parse_kv3(input).expect("expected to parse");
+        assert!(matches!(fields.get("m_nCount"), Some(KV3Value::Int(2))));
+
+        let strict = ParseOptions::new().duplicate_keys(DuplicateKeys::Error);
+        match parse_kv3_with(input, &strict) {
+            Err(Error::Syntax {
+                line,
+                column,
+                expected,
+                ..
+            }) => {
+                assert_eq!((line, column), (4, 2));
+                assert_eq!(expected, "a unique key, `m_nCount` is already set");
+            }
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+
+        let nested = format!(
+            "{}\n{{ m_Child = {{ a = 1 a = 2 }} }}",
+            KV3Header::default()
+        );
+        assert!(parse_kv3_document(&nested).is_ok());
+        assert!(parse_kv3_with(&nested, &strict).is_err());
+
+        // The same key in different objects is fine
+        assert!(parse_kv3_with("{ a = { b = 1 } c = { b = 2 } }", &strict).is_ok());
+    }
+
+    #[test]
+    fn kv3_parse_duplicate_keys_policies() {
+        use crate::{parse_kv3_with, DuplicateKeys, ParseOptions};
+
+        let input = "{ a = 1 b = 2 a = 3 }";
+        let options = |duplicate_keys| ParseOptions::new().duplicate_keys(duplicate_keys);
+
+        let (_, root) =
+            parse_kv3_with(input, &options(DuplicateKeys::FirstWins)).expect("expected to parse");
+        let fields = root_fields(root);
+        assert!(matches!(fields.get("a"), Some(KV3Value::Int(1))));
+        assert_eq!(fields.len(), 2);
+
+        let (_, root) =
+            parse_kv3_with(input, &options(DuplicateKeys::LastWins)).expect("expected to parse");
+        let fields = root_fields(root);
+        assert!(matches!(fields.get("a"), Some(KV3Value::Int(3))));
+        assert_eq!(fields.len(), 2);
+
+        // Keeping all values writes the repeated keys back, in input order
+        // with preserve_order
+        let document = format!(
+            "{}\n{{\n\ta = 1\n\tb = \n\t{{\n\t\tc = 1\n\t\tc = \"x\"\n\t\td = 2\n\t\tc = 3\n\t}}\n\ta = 3\n}}\n",
+            KV3Header::default()
+        );
+        let (_, root) = parse_kv3_with(&document, &options(DuplicateKeys::PreserveAll))
+            .expect("expected to parse");
+        let sorted_lines = |text: &str| {
+            let mut lines: Vec<_> = text.lines().map(str::to_string).collect();
+            lines.sort();
+            lines
+        };
+        assert_eq!(
+            sorted_lines(&to_kv3_string(&root).unwrap()),
+            sorted_lines(&document)
+        );
+
+        #[cfg(feature = "preserve_order")]
+        {
+            assert_eq!(to_kv3_string(&root).unwrap(), document);
+
+            let bytes = crate::binary::to_vec(&root).expect("expected to write binary KV3");
+            let decoded = crate::binary::from_slice(&bytes).expect("expected to read binary KV3");
+            // Binary KV3 stores every entry, the reader keeps the last value
+            assert_eq!(
+                to_kv3_string(&decoded).unwrap(),
+                format!(
+                    "{}\n{{\n\ta = 3\n\tb = \n\t{{\n\t\tc = 3\n\t\td = 2\n\t}}\n}}\n",
+                    KV3Header::default()
+                )
+            );
+        }
+    }
+
+    /// Asserts that parsing fails with `expected` at `line` and `column`.
+    fn assert_syntax_error<T: std::fmt::Debug>(
+        result: Result<T, Error>,
+        line: usize,
+        column: usize,
+        expected: &str,
+    ) {
+        match result {
+            Err(Error::Syntax {
+                line: l,
+                column: c,
+                expected: e,
+                ..
+            }) => assert_eq!((l, c, e.as_str()), (line, column, expected)),
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn kv3_parse_options() {
+        use crate::{parse_kv3_with, CommentStyles, ParseOptions};
+
+        let lenient = ParseOptions::new();
+        let strict = ParseOptions::new().strict(true);
+
+        // Unknown escapes and malformed hex bytes are only accepted leniently
+        let input = "{ path = \"C:\\Steam\" }";
+        assert!(parse_kv3_with(input, &lenient).is_ok());
+        assert_syntax_error(
+            parse_kv3_with(input, &strict),
+            1,
+            13,
+            "a known escape sequence",
+        );
+
+        let input = "{\n\tblob = #[ 00 FF 1 ]\n}";
+        let (_, root) = parse_kv3_with(input, &lenient).expect("expected to parse");
+        assert!(
+            matches!(root_fields(root).get("blob"), Some(KV3Value::HexArray(b)) if b == &[0x00, 0xFF, 0x01])
+        );
+        assert_syntax_error(
+            parse_kv3_with(input, &strict),
+            2,
+            18,
+            "a two-digit hex byte",
+        );
+
+        // Nesting depth counts the root object as 1
+        let input = "{ a = [ { b = [] } ] }";
+        assert!(parse_kv3_with(input, &ParseOptions::new().max_depth(4)).is_ok());
+        assert_syntax_error(
+            parse_kv3_with(input, &ParseOptions::new().max_depth(3)),
+            1,
+            15,
+            "at most 3 levels of nesting",
+        );
+        assert_syntax_error(
+            parse_kv3_with(input, &ParseOptions::new().max_depth(0)),
+            1,
+            1,
+            "at most 0 levels of nesting",
+        );
+
+        // Disabled comment styles are not skipped
+        let input = "{\n\t// line\n\ta = 1 /* block */\n}";
+        assert!(parse_kv3_with(input, &lenient).is_ok());
+        let no_line = ParseOptions::new().comments(CommentStyles {
+            line: false,
+            ..CommentStyles::default()
+        });
+        assert_syntax_error(parse_kv3_with(input, &no_line), 2, 2, "a key or `}`");
+        let no_block = ParseOptions::new().comments(CommentStyles {
+            block: false,
+            ..CommentStyles::default()
+        });
+        assert_syntax_error(parse_kv3_with(input, &no_block), 3, 8, "a key or `}`");
+
+        // The header is optional unless required, and parsed even without
+        // XML-style comments
+        let document = format!("{}\n{{ a = 1 }}", KV3Header::default());
+        let no_xml = ParseOptions::new().comments(CommentStyles {
+            xml: false,
+            ..CommentStyles::default()
+        });
+        let (header, _) = parse_kv3_with(&document, &no_xml).expect("expected to parse");
+        assert_eq!(header, Some(KV3Header::default()));
+
+        let (header, _) = parse_kv3_with("{ a = 1 }", &lenient).expect("expected to parse");
+        assert_eq!(header, None);
+        let required = ParseOptions::new().require_header(true);
+        assert!(parse_kv3_with(&document, &required).is_ok());
+        assert_syntax_error(
+            parse_kv3_with("{ a = 1 }", &required),
+            1,
+            1,
+            "a `<!-- kv3 encoding:... format:... -->` header",
+        );
+    }
+
+    #[test]
+    fn kv3_parse_depth_limit() {
+        use crate::{parse_kv3_with, ParseOptions, DEFAULT_MAX_DEPTH};
+
+        let nested = |depth: usize| {
+            format!(
+                "{{ a = {}{} }}",
+                "[".repeat(depth - 1),
+                "]".repeat(depth - 1)
+            )
+        };
+
+        // The default limit parses without overflowing the stack
+        let (_, fields) = crate::parse_kv3(&nested(DEFAULT_MAX_DEPTH)).expect("expected to parse");
+        assert!(matches!(fields.get("a"), Some(KV3Value::Array(_))));
+
+        match crate::parse_kv3(&nested(DEFAULT_MAX_DEPTH + 1)) {
+            Err(Error::Syntax {
+                line,
+                column,
+                expected,
+                ..
+            }) => {
+                assert_eq!((line, column), (1, 6 + DEFAULT_MAX_DEPTH));
+                assert_eq!(expected, "at most 128 levels of nesting");
+            }
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+
+        // Hostile input fails instead of aborting the process
+        let hostile = format!("{{ a = {}", "[{ b = ".repeat(50_000));
+        assert!(crate::parse_kv3(&hostile).is_err());
+        assert!(crate::kv3_serde::from_str::<TestNestedObj>(&hostile).is_err());
+
+        let unlimited = ParseOptions {
+            max_depth: None,
+            ..ParseOptions::default()
+        };
+        assert!(parse_kv3_with(&nested(DEFAULT_MAX_DEPTH + 1), &unlimited).is_ok());
+
+        // Chained flags are combined rather than nested
+        let chain = format!("{{ a = {}\"x\" }}", "resource:".repeat(50_000));
+        let (_, fields) = crate::parse_kv3(&chain).expect("expected to parse");
+        assert!(matches!(
+            fields.get("a"),
+            Some(KV3Value::Flagged { flags, value })
+                if *flags == KV3Flags::RESOURCE && matches!(**value, KV3Value::String(_))
+        ));
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct WriterRoundTrip {
+        #[serde(rename = "m_nFlags")]
+        flags: i64,
+        scale: f64,
+        name: String,
+        notes: String,
+        blob: Vec<u8>,
+        values: Vec<f64>,
+        children: Vec<SomeObj>,
+        empty: Vec<i32>,
+        nested: NestedObj,
+    }
+
+    #[test]
+    fn kv3_write_round_trip() {
+        let input = r#"
+<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:generic:version{7412167c-06e9-4698-aff2-e63eb59037e7} -->
+{
+    m_nFlags = -3
+    scale = 0.5
+    name = "models/foo.vmdl"
+    notes = """
+line "one"
+line two
+"""
+    blob = #[ 00 01 FE FF ]
+    values = [ 1.0, 2.0, 3.0, 4.0, 5.0 ]
+    children = [ { data = 1 }, { data = 2 } ]
+    empty = []
+    nested = { obj1 = { data = 7 } }
+}
+"#;
+
+        let (_, root) = parse_kv3_document(input).expect("expected to parse document");
+        let written = to_kv3_string(&root).unwrap();
+        let data = serde_kv3::<WriterRoundTrip>(&written).expect("expected to parse output");
+
+        assert_eq!(data.flags, -3);
+        assert_eq!(data.scale, 0.5);
+        assert_eq!(data.name, "models/foo.vmdl");
+        assert_eq!(data.notes, "line \"one\"\nline two");
+        assert_eq!(data.blob, vec![0x00, 0x01, 0xFE, 0xFF]);
+        assert_eq!(data.values, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(data.children.len(), 2);
+        assert_eq!(data.children[1].data, 2);
+        assert!(data.empty.is_empty());
+        assert_eq!(data.nested.obj1.data, 7);
+    }
+
+    #[test]
+    fn kv3_write_layout() {
+        let (_, root) = parse_kv3_document(
+            r#"<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:generic:version{7412167c-06e9-4698-aff2-e63eb59037e7} -->
+{ m_vOffset = [ 1.0, 0.0, -1.5 ] }"#,
+        )
+        .expect("expected to parse document");
+
+        assert_eq!(
+            to_kv3_string(&root).unwrap(),
+            "<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:generic:version{7412167c-06e9-4698-aff2-e63eb59037e7} -->\n{\n\tm_vOffset = [ 1.0, 0.0, -1.5 ]\n}\n"
+        );
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    enum SerializeMode {
+        Additive,
+        Scaled(f64),
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct SerializeChild {
+        #[serde(rename = "m_hModel")]
+        model: String,
+        #[serde(rename = "m_flRadius")]
+        radius: f32,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct SerializeConfig {
+        #[serde(rename = "m_nFlags")]
+        flags: u32,
+        #[serde(rename = "m_bEnabled")]
+        enabled: bool,
+        #[serde(rename = "m_Children")]
+        children: Vec<SerializeChild>,
+        #[serde(rename = "m_Weights")]
+        weights: Vec<i64>,
+        #[serde(rename = "m_Mode")]
+        mode: SerializeMode,
+        #[serde(rename = "m_ScaledMode")]
+        scaled_mode: SerializeMode,
+        #[serde(rename = "m_Description")]
+        description: String,
+        #[serde(rename = "m_Missing")]
+        missing: Option<i32>,
+        #[serde(rename = "m_Present")]
+        present: Option<i32>,
+    }
+
+    #[test]
+    fn kv3_serde_serialize_round_trip() {
+        let config = SerializeConfig {
+            flags: 5,
+            enabled: true,
+            children: vec![
+                SerializeChild {
+                    model: "models/a.vmdl".to_string(),
+                    radius: 1.5,
+                },
+                SerializeChild {
+                    model: "models/b.vmdl".to_string(),
+                    radius: 12.0,
+                },
+            ],
+            weights: vec![1, -2, 3, 4, 5, 6],
+            mode: SerializeMode::Additive,
+            scaled_mode: SerializeMode::Scaled(0.25),
+            description: "first line\nsecond line".to_string(),
+            missing: None,
+            present: Some(3),
+        };
+
+        let text = to_string(&config).expect("expected to serialize");
+        assert!(text.starts_with("<!-- kv3 encoding:text:version{"));
+        assert!(text.contains("m_nFlags = 5"));
+
+        let parsed = serde_kv3::<SerializeConfig>(&text).expect("expected to parse output");
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn kv3_serde_serialize_rejects_non_string_keys() {
+        let mut map = std::collections::HashMap::new();
+        map.insert(vec![1], 1);
+
+        assert!(to_string(&map).is_err());
+    }
+
+    #[test]
+    fn kv3_write_rejects_non_object_root() {
+        let invalid_input = |err: &Error| matches!(err, Error::Io(e) if e.kind() == std::io::ErrorKind::InvalidInput);
+
+        for value in [
+            KV3Value::Int(5),
+            KV3Value::Array(vec![KV3Value::Int(1)]),
+            KV3Value::Null,
+        ] {
+            let err = to_kv3_string(&value).expect_err("expected a non-object root to fail");
+            assert!(invalid_input(&err), "{:?}", err);
+        }
+
+        let err = to_string(&vec![1, 2]).expect_err("expected a non-object root to fail");
+        assert!(invalid_input(&err), "{:?}", err);
+        assert!(to_string(&5).is_err());
+        assert!(to_kv3_string(&KV3Value::Object(Default::default())).is_ok());
+    }
+
+    #[cfg(feature = "preserve_order")]
+    const ORDER_INPUT: &str = r#"
+{
+    m_zeta = 1
+    m_alpha = 2
+    m_nested =
+    {
+        m_b = 3
+        m_a = 4
+        m_c = 5
+    }
+    m_middle = 6
+}
+"#;
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn kv3_parse_preserves_key_order() {
+        let (_, root) = crate::parse_kv3(ORDER_INPUT).expect("expected to parse");
+        let keys: Vec<&str> = root.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["m_zeta", "m_alpha", "m_nested", "m_middle"]);
+
+        let written = to_kv3_string(&KV3Value::Object(crate::KV3Object::new(root))).unwrap();
+        let written_keys: Vec<&str> = written
+            .lines()
+            .filter_map(|line| line.split_once(" = ").map(|(key, _)| key.trim()))
+            .collect();
+        assert_eq!(
+            written_keys,
+            ["m_zeta", "m_alpha", "m_nested", "m_b", "m_a", "m_c", "m_middle"]
+        );
+    }
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn kv3_serde_preserves_key_order() {
+        let value = serde_kv3::<KV3Value>(ORDER_INPUT).expect("expected to parse");
+        let written = to_kv3_string(&value).unwrap();
+        let first = written.find("m_zeta").unwrap();
+        let second = written.find("m_alpha").unwrap();
+        let nested_b = written.find("m_b").unwrap();
+        let nested_a = written.find("m_a ").unwrap();
+
+        assert!(first < second);
+        assert!(nested_b < nested_a);
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct FlaggedTest {
+        #[serde(rename = "m_hModel")]
+        model: String,
+        #[serde(rename = "m_sound")]
+        sound: String,
+        #[serde(rename = "m_Entity")]
+        entity: String,
+    }
+
+    const FLAGGED_INPUT: &str = r#"
+<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:generic:version{7412167c-06e9-4698-aff2-e63eb59037e7} -->
+{
+    m_hModel = resource:"models/foo.vmdl"
+    m_sound = soundevent:"weapon.fire"
+    m_Entity = resource_name|subclass:"info_target"
+}
+"#;
+
+    #[test]
+    fn kv3_parse_flagged_values() {
+        let data = serde_kv3::<FlaggedTest>(FLAGGED_INPUT).expect("expected to parse");
+        assert_eq!(data.model, "models/foo.vmdl");
+        assert_eq!(data.sound, "weapon.fire");
+        assert_eq!(data.entity, "info_target");
+
+        let (_, root) = parse_kv3_document(FLAGGED_INPUT).expect("expected to parse");
+        let written = to_kv3_string(&root).unwrap();
+        assert!(written.contains(r#"m_hModel = resource:"models/foo.vmdl""#));
+        assert!(written.contains(r#"m_sound = soundevent:"weapon.fire""#));
+        assert!(written.contains(r#"m_Entity = resource_name|subclass:"info_target""#));
+    }
+
+    #[test]
+    fn kv3_parse_flagged_value_flags() {
+        let (_, root) = parse_kv3_document(FLAGGED_INPUT).expect("expected to parse");
+        let written = to_kv3_string(&root).unwrap();
+        let (_, reparsed) = parse_kv3_document(&written).expect("expected to reparse");
+
+        match reparsed {
+            KV3Value::Object(obj) => match obj.fields.get("m_Entity") {
+                Some(KV3Value::Flagged { flags, value }) => {
+                    assert_eq!(*flags, KV3Flags::RESOURCE_NAME | KV3Flags::SUBCLASS);
+                    assert!(matches!(value.as_ref(), KV3Value::String(s) if s == "info_target"));
+                }
+                other => panic!("expected a flagged value, got {:?}", other),
+            },
+            other => panic!("expected an object, got {:?}", other),
+        }
+
+        // Unknown flags are skipped instead of failing the document
+        let input = "{ a = panel:\"p.xml\" b = unknown:\"foo\" c = resource|unknown:\"bar\" }";
+        let (_, root) =
+            crate::parse_kv3_with(input, &Default::default()).expect("expected to parse");
+        assert_eq!(
+            root["a"],
+            KV3Value::Flagged {
+                flags: KV3Flags::PANEL,
+                value: Box::new(KV3Value::from("p.xml")),
+            }
+        );
+        assert_eq!(root["b"], KV3Value::from("foo"));
+        assert_eq!(
+            root["c"],
+            KV3Value::Flagged {
+                flags: KV3Flags::RESOURCE,
+                value: Box::new(KV3Value::from("bar")),
+            }
+        );
+        let written = to_kv3_string(&root).unwrap();
+        assert!(written.contains("a = panel:\"p.xml\"\n"), "{}", written);
+        assert!(written.contains("b = \"foo\"\n"), "{}", written);
+    }
+
+    #[test]
+    fn kv3_parse_flagged_value_whitespace() {
+        let input = "{ m_hModel = resource: \"models/foo.vmdl\" m_sound = soundevent: /* fire */\n\tresource_name:\"weapon.fire\" }";
+        let (_, root) =
+            crate::parse_kv3_with(input, &Default::default()).expect("expected to parse");
+        let fields = root_fields(root);
+        assert!(matches!(
+            fields.get("m_hModel"),
+            Some(KV3Value::Flagged { flags, value })
+                if *flags == KV3Flags::RESOURCE && matches!(value.as_ref(), KV3Value::String(s) if s == "models/foo.vmdl")
+        ));
+        assert!(matches!(
+            fields.get("m_sound"),
+            Some(KV3Value::Flagged { flags, value })
+                if *flags == KV3Flags::SOUNDEVENT | KV3Flags::RESOURCE_NAME
+                    && matches!(value.as_ref(), KV3Value::String(_))
+        ));
+    }
+
+    #[test]
+    fn kv3_binary_unknown_flags_to_text() {
+        use crate::binary::{self, ENCODING_BINARY_UNCOMPRESSED};
+
+        let mut payload = Vec::new();
+        let int = |out: &mut Vec<u8>, v: i32| out.extend_from_slice(&v.to_le_bytes());
+        int(&mut payload, 3);
+        payload.extend(b"m_a\0x\0m_b\0");
+        payload.push(9); // object
+        int(&mut payload, 2);
+        int(&mut payload, 0);
+        payload.extend([0x86, 0x40]); // string with only an unnamed flag bit
+        int(&mut payload, 1);
+        int(&mut payload, 2);
+        payload.extend([0x86, 0x81]); // string with `resource` and bit 7
+        int(&mut payload, 1);
+        payload.extend([0xFF; 4]);
+
+        let decoded = binary::from_slice(&binary_legacy(ENCODING_BINARY_UNCOMPRESSED, &payload))
+            .expect("expected to read binary KV3");
+        let text = to_kv3_string(&decoded).unwrap();
+        assert!(text.contains("m_a = \"x\"\n"), "{}", text);
+        assert!(text.contains("m_b = resource:\"x\"\n"), "{}", text);
+
+        let (_, reparsed) = parse_kv3_document(&text).expect("expected to reparse");
+        let fields = root_fields(reparsed);
+        assert!(matches!(fields.get("m_a"), Some(KV3Value::String(s)) if s == "x"));
+        assert!(matches!(
+            fields.get("m_b"),
+            Some(KV3Value::Flagged { flags, .. }) if *flags == KV3Flags::RESOURCE
+        ));
+    }
+
+    const BINARY_STRINGS: [&str; 8] = [
+        "name",
+        "foo",
+        "count",
+        "flag",
+        "m_hModel",
+        "models/a.vmdl",
+        "values",
+        "blob",
+    ];
+
+    const GENERIC_FORMAT_GUID: [u8; 16] = [
+        0x7C, 0x16, 0x12, 0x74, 0xE9, 0x06, 0x98, 0x46, 0xAF, 0xF2, 0xE6, 0x3E, 0xB5, 0x90, 0x37,
+        0xE7,
+    ];
+
+    #[derive(Debug, Deserialize)]
+    struct BinaryTest {
+        name: String,
+        count: i32,
+        flag: bool,
+        #[serde(rename = "m_hModel")]
+        model: String,
+        values: Vec<f64>,
+        blob: Vec<u8>,
+    }
+
+    fn binary_string_table() -> Vec<u8> {
+        let mut out = Vec::new();
+        for s in BINARY_STRINGS {
+            out.extend_from_slice(s.as_bytes());
+            out.push(0);
+        }
+        out
+    }
+
+    /// `VKV\x03` payload with types and data interleaved.
+    fn binary_legacy_payload() -> Vec<u8> {
+        let mut out = Vec::new();
+        let int = |out: &mut Vec<u8>, v: i32| out.extend_from_slice(&v.to_le_bytes());
+        int(&mut out, BINARY_STRINGS.len() as i32);
+        out.extend(binary_string_table());
+        out.push(9); // object
+        int(&mut out, 6);
+        int(&mut out, 0);
+        out.push(6); // string
+        int(&mut out, 1);
+        int(&mut out, 2);
+        out.push(11); // int32
+        int(&mut out, 7);
+        int(&mut out, 3);
+        out.push(13); // true
+        int(&mut out, 4);
+        out.extend([0x86, 0x01]); // resource: string
+        int(&mut out, 5);
+        int(&mut out, 6);
+        out.push(10); // typed array
+        int(&mut out, 2);
+        out.push(5); // of doubles
+        out.extend(1.5f64.to_le_bytes());
+        out.extend(2.5f64.to_le_bytes());
+        int(&mut out, 7);
+        out.push(7); // blob
+        int(&mut out, 3);
+        out.extend([1, 2, 3]);
+        out.extend([0xFF; 4]);
+        out
+    }
+
+    fn binary_legacy(encoding: [u8; 16], payload: &[u8]) -> Vec<u8> {
+        let mut out = b"VKV\x03".to_vec();
+        out.extend(encoding);
+        out.extend(GENERIC_FORMAT_GUID);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// Literal-and-back-reference encoder for Valve's block compression.
+    fn binary_block_compress(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_le_bytes().to_vec();
+        let mut pos = 0;
+        while pos < data.len() {
+            let mask_pos = out.len();
+            out.extend([0, 0]);
+            let mut mask = 0u16;
+            for bit in 0..16 {
+                if pos >= data.len() {
+                    break;
+                }
+                let window = pos.saturating_sub(4096);
+                let best = (window..pos)
+                    .map(|start| {
+                        let len = (0..18)
+                            .take_while(|i| {
+                                pos + i < data.len() && data[start + i] == data[pos + i]
+                            })
+                            .count();
+                        (len, pos - start)
+                    })
+                    .max();
+                match best {
+                    Some((len, offset)) if len >= 3 => {
+                        mask |= 1 << bit;
+                        let token = (((offset - 1) as u16) << 4) | (len - 3) as u16;
+                        out.extend(token.to_le_bytes());
+                        pos += len;
+                    }
+                    _ => {
+                        out.push(data[pos]);
+                        pos += 1;
+                    }
+                }
+            }
+            out[mask_pos..mask_pos + 2].copy_from_slice(&mask.to_le_bytes());
+        }
+        out
+    }
+
+    /// `\x013VK` document with separate sections for each kind of data.
+    fn binary_v1() -> Vec<u8> {
+        let types = [9u8, 6, 11, 13, 0x86, 0x01, 10, 5, 7];
+        let ints: [i32; 13] = [8, 6, 0, 1, 2, 7, 3, 4, 5, 6, 2, 7, 3];
+
+        let mut buffer = vec![1u8, 2, 3, 0];
+        for v in ints {
+            buffer.extend(v.to_le_bytes());
+        }
+        buffer.extend(1.5f64.to_le_bytes());
+        buffer.extend(2.5f64.to_le_bytes());
+        buffer.extend(binary_string_table());
+        buffer.extend(types);
+        buffer.extend(0xFFEEDD00u32.to_le_bytes());
+
+        let mut out = b"\x013VK".to_vec();
+        out.extend(GENERIC_FORMAT_GUID);
+        for v in [
+            0,
+            3,
+            ints.len() as u32,
+            2,
+            buffer.len() as u32,
+            buffer.len() as u32,
+            0,
+            0,
+        ] {
+            out.extend(v.to_le_bytes());
+        }
+        out.extend(buffer);
+        out
+    }
+
+    /// `\x023VK` to `\x053VK` document laid out by hand from the published
+    /// format, with the blob stored in the block data after the buffers.
+    /// Version 5 keeps the string table in the first buffer and the values,
+    /// types and block sizes in the second.
+    fn binary_versioned(version: u8) -> Vec<u8> {
+        fn section(buffer: &mut Vec<u8>, align: usize, data: impl IntoIterator<Item = u8>) {
+            while !buffer.len().is_multiple_of(align) {
+                buffer.push(0);
+            }
+            buffer.extend(data);
+        }
+        let ints = |values: &[i32]| {
+            values
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+
+        let strings = binary_string_table();
+        let types = [9u8, 6, 11, 13, 0x86, 0x01, 10, 5, 7];
+        let values: [i32; 11] = [6, 0, 1, 2, 7, 3, 4, 5, 6, 2, 7];
+        let doubles = [1.5f64, 2.5]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let mut tail = types.to_vec();
+        tail.extend(3u32.to_le_bytes()); // block sizes
+        tail.extend(0xFFEEDD00u32.to_le_bytes());
+
+        // Section counts (bytes, ints, doubles) for the first buffer
+        let mut buffer0 = Vec::new();
+        let (counts0, buffer1) = if version >= 5 {
+            section(&mut buffer0, 4, ints(&[8]));
+            buffer0.extend(&strings);
+
+            let mut buffer1 = Vec::new();
+            section(&mut buffer1, 4, ints(&values));
+            section(&mut buffer1, 8, doubles);
+            buffer1.extend(tail);
+            ([0, 1, 0], Some(buffer1))
+        } else {
+            section(&mut buffer0, 4, ints(&[8]));
+            buffer0.extend(ints(&values));
+            section(&mut buffer0, 8, doubles);
+            buffer0.extend(&strings);
+            buffer0.extend(tail);
+            ([0, 12, 2], None)
+        };
+
+        let mut out = vec![version, b'3', b'V', b'K'];
+        out.extend(GENERIC_FORMAT_GUID);
+        out.extend(0u32.to_le_bytes()); // uncompressed
+        out.extend(0u16.to_le_bytes()); // dictionary id
+        out.extend(0u16.to_le_bytes()); // frame size
+        for count in counts0 {
+            out.extend((count as u32).to_le_bytes());
+        }
+        out.extend(((strings.len() + types.len()) as u32).to_le_bytes());
+        out.extend(1u16.to_le_bytes()); // objects
+        out.extend(1u16.to_le_bytes()); // arrays
+        out.extend((buffer0.len() as u32).to_le_bytes());
+        out.extend((buffer0.len() as u32).to_le_bytes());
+        out.extend(1u32.to_le_bytes()); // block count
+        out.extend(3u32.to_le_bytes()); // block total size
+        if version >= 4 {
+            out.extend(0u32.to_le_bytes()); // shorts
+            out.extend(0u32.to_le_bytes()); // reserved
+        }
+        if let Some(buffer1) = &buffer1 {
+            out.extend((buffer1.len() as u32).to_le_bytes());
+            out.extend((buffer1.len() as u32).to_le_bytes());
+            for count in [0u32, 0, values.len() as u32, 2] {
+                out.extend(count.to_le_bytes());
+            }
+        }
+        out.extend(buffer0);
+        out.extend(buffer1.unwrap_or_default());
+        out.extend([1, 2, 3]); // block data
+        out
+    }
+
+    fn assert_binary_test(data: &[u8]) {
+        let parsed = crate::kv3_serde::serde_kv3_binary::<BinaryTest>(data)
+            .expect("expected to parse binary kv3");
+        assert_eq!(parsed.name, "foo");
+        assert_eq!(parsed.count, 7);
+        assert!(parsed.flag);
+        assert_eq!(parsed.model, "models/a.vmdl");
+        assert_eq!(parsed.values, vec![1.5, 2.5]);
+        assert_eq!(parsed.blob, vec![1, 2, 3]);
+
+        match crate::binary::from_slice(data).expect("expected to parse binary kv3") {
+            KV3Value::Object(obj) => assert!(matches!(
+                obj.fields.get("m_hModel"),
+                Some(KV3Value::Flagged { flags, .. }) if *flags == KV3Flags::RESOURCE
+            )),
+            other => panic!("expected an object, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn kv3_binary_legacy() {
+        let uncompressed = [
+            0x00, 0x05, 0x86, 0x1B, 0xD8, 0xF7, 0xC1, 0x40, 0xAD, 0x82, 0x75, 0xA4, 0x82, 0x67,
+            0xE7, 0x14,
+        ];
+        let block_compressed = [
+            0x46, 0x1A, 0x79, 0x95, 0xBC, 0x95, 0x6C, 0x4F, 0xA7, 0x0B, 0x05, 0xBC, 0xA1, 0xB7,
+            0xDF, 0xD2,
+        ];
+        let payload = binary_legacy_payload();
+
+        assert_eq!(
+            crate::binary::Version::detect(&binary_legacy(uncompressed, &payload)),
+            Some(crate::binary::Version::Legacy)
+        );
+        assert_binary_test(&binary_legacy(uncompressed, &payload));
+        assert_binary_test(&binary_legacy(
+            block_compressed,
+            &binary_block_compress(&payload),
+        ));
+    }
+
+    #[test]
+    fn kv3_binary_v1() {
+        let data = binary_v1();
+        assert_eq!(
+            crate::binary::Version::detect(&data),
+            Some(crate::binary::Version::V1)
+        );
+        assert_binary_test(&data);
+
+        assert!(matches!(
+            crate::binary::from_slice(&data[..data.len() - 1]),
+            Err(crate::binary::BinaryError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            crate::binary::from_slice(b"VKV\x04"),
+            Err(crate::binary::BinaryError::UnknownMagic(_))
+        ));
+    }
+
+    #[test]
+    fn kv3_binary_versioned() {
+        use crate::binary::Version;
+
+        for (version, expected) in [
+            (2, Version::V2),
+            (3, Version::V3),
+            (4, Version::V4),
+            (5, Version::V5),
+        ] {
+            let data = binary_versioned(version);
+            assert_eq!(Version::detect(&data), Some(expected));
+            assert_binary_test(&data);
+        }
+    }
+
+    #[test]
+    fn kv3_binary_unknown_types() {
+        let data = binary_v1();
+        let types = [9u8, 6, 11, 13, 0x86, 0x01, 10, 5, 7];
+        let start = data
+            .windows(types.len())
+            .position(|window| window == types)
+            .unwrap();
+
+        // Codes 22 and 23 have no known layout, so they are rejected
+        for ty in [22, 23] {
+            let mut data = data.clone();
+            data[start + 3] = ty;
+            assert!(matches!(
+                crate::binary::from_slice(&data),
+                Err(crate::binary::BinaryError::UnknownType(t)) if t == ty
+            ));
+        }
+    }
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn kv3_binary_write_round_trip() {
+        use crate::binary::{self, Version, WriteOptions};
+
+        let input = r#"
+<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:generic:version{7412167c-06e9-4698-aff2-e63eb59037e7} -->
+{
+    m_nZero = 0
+    m_nOne = 1
+    m_nSmall = -42
+    m_nLarge = 9007199254740993
+    m_nHash = 18446744073709551615
+    m_flZero = 0.0
+    m_flOne = 1.0
+    m_flValue = -12.25
+    m_bEnabled = true
+    m_bDisabled = false
+    m_sEmpty = ""
+    m_sName = "name"
+    m_sRepeated = "name"
+    m_hModel = resource:"models/foo.vmdl"
+    m_Blob = #[ 00 01 02 FF ]
+    m_EmptyBlob = #[]
+    m_Null = null
+    m_Array = [ 1, "two", [ 3.5 ], { m_nNested = 4 } ]
+    m_Object =
+    {
+        m_Blob = #[ AB CD ]
+        m_Empty = {}
+    }
+}
+"#;
+        let (_, root) = parse_kv3_document(input).expect("expected to parse");
+        let expected = to_kv3_string(&root).unwrap();
+
+        for version in [
+            Version::Legacy,
+            Version::V1,
+            Version::V2,
+            Version::V3,
+            Version::V4,
+            Version::V5,
+        ] {
+            let options = WriteOptions {
+                version,
+                ..WriteOptions::default()
+            };
+            let data = binary::to_vec_with(&root, &options).expect("expected to write binary kv3");
+            assert_eq!(Version::detect(&data), Some(version));
+
+            let decoded = binary::from_slice(&data).expect("expected to read binary kv3");
+            assert_eq!(to_kv3_string(&decoded).unwrap(), expected, "{:?}", version);
+        }
+    }
+
+    /// Writes and reads back an object with blobs spanning several LZ4 chunks.
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn assert_binary_compressed_round_trip(
+        compression: crate::binary::Compression,
+        versions: &[crate::binary::Version],
+    ) {
+        use crate::binary::{self, WriteOptions};
+
+        let large: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let input = format!(
+            r#"<!-- kv3 encoding:text:version{{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d}} format:generic:version{{7412167c-06e9-4698-aff2-e63eb59037e7}} -->
+{{
+    m_sName = "name"
+    m_nValue = 12345
+    m_Small = #[ 01 02 03 ]
+    m_Large = #[ {} ]
+    m_Array = [ 1.5, "two", resource:"models/a.vmdl" ]
+}}
+"#,
+            large
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        let (_, root) = parse_kv3_document(&input).expect("expected to parse");
+        let expected = to_kv3_string(&root).unwrap();
+
+        for &version in versions {
+            let options = WriteOptions {
+                version,
+                compression,
+            };
+            let data = binary::to_vec_with(&root, &options).expect("expected to write binary kv3");
+            let decoded = binary::from_slice(&data).expect("expected to read binary kv3");
+            assert_eq!(to_kv3_string(&decoded).unwrap(), expected, "{:?}", version);
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn kv3_binary_lz4() {
+        use crate::binary::{Compression, Version};
+
+        assert_binary_compressed_round_trip(
+            Compression::Lz4,
+            &[
+                Version::Legacy,
+                Version::V1,
+                Version::V2,
+                Version::V3,
+                Version::V4,
+                Version::V5,
+            ],
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn kv3_binary_zstd() {
+        use crate::binary::{Compression, Version};
+
+        assert_binary_compressed_round_trip(
+            Compression::Zstd,
+            &[
+                Version::V1,
+                Version::V2,
+                Version::V3,
+                Version::V4,
+                Version::V5,
+            ],
+        );
+    }
+
+    /// Sizes in a header are checked against the compressed data instead of
+    /// being allocated up front.
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn kv3_binary_decompression_size_limits() {
+        use crate::binary::{self, BinaryError, Compression, Version, WriteOptions};
+
+        // Version 1 documents keep the uncompressed buffer size at 36
+        let with_size = |mut data: Vec<u8>, size: u32| {
+            data[36..40].copy_from_slice(&size.to_le_bytes());
+            data
+        };
+        let root = KV3Value::from(std::collections::HashMap::from([("m_Data", vec![0u8; 64])]));
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let options = WriteOptions {
+                version: Version::V1,
+                compression,
+            };
+            let Ok(data) = binary::to_vec_with(&root, &options) else {
+                continue;
+            };
+            assert!(matches!(
+                binary::from_slice(&with_size(data.clone(), u32::MAX)),
+                Err(BinaryError::Decompression(_) | BinaryError::Malformed(_))
+            ));
+            assert!(matches!(
+                binary::from_slice(&with_size(data, 16)),
+                Err(BinaryError::Decompression(_))
+            ));
+        }
+
+        // A Zstandard frame asking for a 2 TiB window
+        let frame = [0x28, 0xB5, 0x2F, 0xFD, 0x00, 0xF8, 0, 0, 0];
+        let mut data = b"\x013VK".to_vec();
+        data.extend(GENERIC_FORMAT_GUID);
+        for v in [2, 0, 1, 0, 64, frame.len() as u32, 0, 0] {
+            data.extend(v.to_le_bytes());
+        }
+        data.extend(frame);
+        let err = binary::from_slice(&data).expect_err("expected an error");
+        assert_eq!(
+            matches!(err, BinaryError::Decompression(_)),
+            cfg!(feature = "zstd")
+        );
+    }
+
+    #[test]
+    fn kv3_binary_depth_limit() {
+        use crate::binary::{self, BinaryError, ReadOptions, ENCODING_BINARY_UNCOMPRESSED};
+        use crate::DEFAULT_MAX_DEPTH;
+
+        // Arrays holding a single array, `depth` levels deep
+        let nested = |depth: usize| {
+            let mut payload = 0u32.to_le_bytes().to_vec();
+            for _ in 0..depth {
+                payload.push(8); // array
+                payload.extend(1u32.to_le_bytes());
+            }
+            payload.push(1); // null
+            payload.extend(0xFFFFFFFFu32.to_le_bytes());
+            binary_legacy(ENCODING_BINARY_UNCOMPRESSED, &payload)
+        };
+
+        assert!(binary::from_slice(&nested(DEFAULT_MAX_DEPTH)).is_ok());
+        let err =
+            binary::from_slice(&nested(DEFAULT_MAX_DEPTH + 1)).expect_err("expected an error");
+        assert!(matches!(err, BinaryError::DepthLimitExceeded(128)));
+        assert_eq!(err.to_string(), "binary KV3 nests deeper than 128 levels");
+
+        // Hostile input fails instead of aborting the process
+        assert!(matches!(
+            binary::from_slice(&nested(100_000)),
+            Err(BinaryError::DepthLimitExceeded(_))
+        ));
+
+        let options = ReadOptions {
+            max_depth: Some(DEFAULT_MAX_DEPTH + 1),
+        };
+        assert!(binary::from_slice_with(&nested(DEFAULT_MAX_DEPTH + 1), &options).is_ok());
+    }
+
+    #[test]
+    fn kv3_binary_compression_errors() {
+        use crate::binary::{self, BinaryError, Compression};
+
+        // Replace the compression method of a version 1 document
+        let mut data = binary_v1();
+        data[20..24].copy_from_slice(&7u32.to_le_bytes());
+        assert!(matches!(
+            binary::from_slice(&data),
+            Err(BinaryError::UnknownCompression(7))
+        ));
+
+        data[20..24].copy_from_slice(&1u32.to_le_bytes());
+        let err = binary::from_slice(&data).expect_err("expected invalid LZ4 data");
+        if cfg!(feature = "lz4") {
+            assert!(!matches!(err, BinaryError::UnsupportedCompression(_)));
+        } else {
+            assert!(matches!(
+                err,
+                BinaryError::UnsupportedCompression(Compression::Lz4)
+            ));
+            assert_eq!(
+                err.to_string(),
+                "LZ4 compressed binary KV3 requires the `lz4` feature of kv3"
+            );
+        }
+
+        data[20..24].copy_from_slice(&2u32.to_le_bytes());
+        let err = binary::from_slice(&data).expect_err("expected invalid Zstandard data");
+        assert_eq!(
+            matches!(err, BinaryError::UnsupportedCompression(Compression::Zstd)),
+            !cfg!(feature = "zstd")
+        );
+    }
+
+    #[test]
+    fn kv3_document_edit() {
+        use crate::document::Document;
+
+        let input = r#"<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:generic:version{7412167c-06e9-4698-aff2-e63eb59037e7} -->
+{
+    // Spawn limit
+    m_nMaxParticles = 16   // keep low
+
+    /* Renderers */
+    m_Renderers =
+    [
+        {
+            m_hTexture = resource:"materials/particle/fire.vtex"
+            m_flAlphaScale = 1.000
+        },
+        { m_flAlphaScale = 0.5 },
+    ]
+    m_Empty = {}
+}
+"#;
+
+        let mut doc: Document = input.parse().unwrap();
+        assert_eq!(doc.header().map(|h| h.format.as_str()), Some("generic"));
+        assert_eq!(doc.to_string(), input);
+        assert!(matches!(
+            doc.get("m_nMaxParticles"),
+            Some(KV3Value::Int(16))
+        ));
+        assert_eq!(doc.get_text("m_Renderers[0].m_flAlphaScale"), Some("1.000"));
+        assert!(matches!(
+            doc.get("m_Renderers[1][\"m_flAlphaScale\"]"),
+            Some(KV3Value::Double(d)) if d == 0.5
+        ));
+        assert!(doc.get("m_Renderers[2]").is_none());
+        assert!(doc.get("m_Renderers..x").is_none());
+
+        doc.set("m_nMaxParticles", &KV3Value::Int(32)).unwrap();
+        doc.set("m_Renderers[0].m_flAlphaScale", &KV3Value::Double(0.25))
+            .unwrap();
+        doc.set("m_Renderers[0].m_bFogParticles", &KV3Value::Bool(true))
+            .unwrap();
+        doc.set("m_Empty.m_nCount", &KV3Value::Int(1)).unwrap();
+        assert!(matches!(
+            doc.remove("m_Renderers[1]"),
+            Some(KV3Value::Object(_))
+        ));
+        assert!(doc.remove("m_Renderers[1]").is_none());
+
+        let expected = r#"<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:generic:version{7412167c-06e9-4698-aff2-e63eb59037e7} -->
+{
+    // Spawn limit
+    m_nMaxParticles = 32   // keep low
+
+    /* Renderers */
+    m_Renderers =
+    [
+        {
+            m_hTexture = resource:"materials/particle/fire.vtex"
+            m_flAlphaScale = 0.25
+            m_bFogParticles = true
+        },
+    ]
+    m_Empty = {
+        m_nCount = 1
+    }
+}
+"#;
+        assert_eq!(doc.as_str(), expected);
+
+        // Invalid edits fail and leave the document unchanged
+        assert!(matches!(
+            doc.set("m_Renderers[5]", &KV3Value::Null),
+            Err(Error::Path { .. })
+        ));
+        assert!(matches!(
+            doc.set("m_nMaxParticles.x", &KV3Value::Null),
+            Err(Error::Path { .. })
+        ));
+        assert!(matches!(
+            doc.set("m_Renderers[", &KV3Value::Null),
+            Err(Error::Path { .. })
+        ));
+        assert_eq!(doc.as_str(), expected);
+    }
+
+    #[test]
+    fn kv3_document_append_after_trailing_comment() {
+        use crate::document::Document;
+
+        let mut doc = Document::parse("{\n\ta = 1 // first\n\tb = 2 // last\n}\n").unwrap();
+        doc.set("c", &KV3Value::Int(3)).unwrap();
+        assert_eq!(
+            doc.as_str(),
+            "{\n\ta = 1 // first\n\tb = 2 // last\n\tc = 3\n}\n"
+        );
+
+        let mut doc = Document::parse("{\n\ta = 1 /* last */\n}\n").unwrap();
+        doc.set("b", &KV3Value::Int(2)).unwrap();
+        assert_eq!(doc.as_str(), "{\n\ta = 1 /* last */\n\tb = 2\n}\n");
+
+        // A block comment is skipped to its end, however many lines it takes
+        let mut doc = Document::parse("{\n\ta = 1 /* multi\n\tline */\n}\n").unwrap();
+        doc.set("b", &KV3Value::Int(2)).unwrap();
+        assert_eq!(doc.as_str(), "{\n\ta = 1 /* multi\n\tline */\n\tb = 2\n}\n");
+    }
+
+    #[test]
+    fn kv3_document_append_to_inline_object() {
+        use crate::document::Document;
+        use crate::KV3Object;
+
+        let mut doc = Document::parse("{ a = 1 }").unwrap();
+        doc.set("b", &KV3Value::Int(2)).unwrap();
+        assert_eq!(doc.as_str(), "{ a = 1 b = 2 }");
+
+        // An entry spanning several lines spreads the object out
+        let mut doc = Document::parse("{\n\tm = { a = 1 /* one */ b = { c = 2 } }\n}\n").unwrap();
+        let mut object = KV3Object::default();
+        object.insert("d".to_string(), KV3Value::Int(3));
+        doc.set("m.x", &KV3Value::Object(object)).unwrap();
+        assert_eq!(
+            doc.as_str(),
+            "{\n\tm = {\n\t\ta = 1 /* one */\n\t\tb = { c = 2 }\n\t\tx = \n\t\t{\n\t\t\td = 3\n\t\t}\n\t}\n}\n"
+        );
+        assert_eq!(doc.get("m.x.d"), Some(KV3Value::Int(3)));
+    }
+
+    #[test]
+    fn kv3_document_remove_from_shared_line() {
+        use crate::document::Document;
+
+        let mut doc = Document::parse("{\n\tb = 2 c = 3\n}\n").unwrap();
+        assert_eq!(doc.remove("b"), Some(KV3Value::Int(2)));
+        assert_eq!(doc.as_str(), "{\n\tc = 3\n}\n");
+
+        let mut doc = Document::parse("{\n\tb = 2 c = 3\n}\n").unwrap();
+        assert_eq!(doc.remove("c"), Some(KV3Value::Int(3)));
+        assert_eq!(doc.as_str(), "{\n\tb = 2\n}\n");
+
+        let mut doc = Document::parse("{ a = [ 1, 2, 3 ] }").unwrap();
+        assert_eq!(doc.remove("a[1]"), Some(KV3Value::Int(2)));
+        assert_eq!(doc.as_str(), "{ a = [ 1, 3 ] }");
+    }
+
+    #[test]
+    fn kv3_value_accessors() {
+        use crate::{parse_kv3_with, ParseOptions};
+
+        let input = r#"{
+            m_bEnabled = true
+            m_nCount = 3
+            m_nHash = 18446744073709551615
+            m_flScale = 0.5
+            m_hModel = resource:"models/foo.vmdl"
+            m_Data = #[ 01 02 ]
+            m_Items = [ { m_nId = 7 }, null ]
+        }"#;
+        let (_, mut root) = parse_kv3_with(input, &ParseOptions::new().keep_number_text(true))
+            .expect("expected to parse");
+
+        assert_eq!(root["m_bEnabled"].as_bool(), Some(true));
+        assert_eq!(root["m_nCount"].as_i64(), Some(3));
+        assert_eq!(root["m_nCount"].as_u64(), Some(3));
+        assert_eq!(root["m_nCount"].as_f64(), Some(3.0));
+        assert_eq!(root["m_nHash"].as_i64(), None);
+        assert_eq!(root["m_nHash"].as_u64(), Some(u64::MAX));
+        assert_eq!(root["m_flScale"].as_f64(), Some(0.5));
+        assert_eq!(root["m_flScale"].as_i64(), None);
+        assert_eq!(root["m_hModel"].as_str(), Some("models/foo.vmdl"));
+        assert_eq!(root["m_Data"].as_bytes(), Some(&[1u8, 2][..]));
+        assert_eq!(root["m_Items"][0]["m_nId"].as_i64(), Some(7));
+        assert!(root["m_Items"][1].is_null());
+        assert!(root["m_Items"][2].is_null());
+        assert!(root["m_Missing"]["m_nId"].is_null());
+        assert!(root["m_nCount"][0].is_null());
+
+        let obj = root.as_object_mut().expect("expected an object");
+        assert_eq!(obj.len(), 7);
+        assert!(obj.contains_key("m_Data"));
+        assert!(obj.remove("m_Data").is_some());
+        assert!(obj.remove("m_Data").is_none());
+        assert!(obj.insert("m_nCount", KV3Value::Int(4)).is_some());
+        assert!(obj.insert("m_bNew", KV3Value::Null).is_none());
+        *obj.get_mut("m_flScale").expect("expected m_flScale") = KV3Value::Double(2.0);
+        root.as_object_mut()
+            .and_then(|obj| obj.get_mut("m_Items"))
+            .and_then(KV3Value::as_array_mut)
+            .expect("expected an array")
+            .pop();
+        assert_eq!(root["m_nCount"].as_i64(), Some(4));
+        assert_eq!(root["m_flScale"].as_f64(), Some(2.0));
+        assert_eq!(root["m_Items"].as_array().map(Vec::len), Some(1));
+
+        #[cfg(feature = "preserve_order")]
+        {
+            use crate::DuplicateKeys;
+
+            let keys: Vec<_> = root
+                .as_object()
+                .expect("expected an object")
+                .keys()
+                .collect();
+            assert_eq!(
+                keys,
+                [
+                    "m_bEnabled",
+                    "m_nCount",
+                    "m_nHash",
+                    "m_flScale",
+                    "m_hModel",
+                    "m_Items",
+                    "m_bNew"
+                ]
+            );
+
+            // Inserting or removing a repeated key affects all its values
+            let input = "{ a = 1 b = 2 a = 3 c = 4 a = 5 d = 6 }";
+            let options = ParseOptions::new().duplicate_keys(DuplicateKeys::PreserveAll);
+            let (_, mut root) = parse_kv3_with(input, &options).expect("expected to parse");
+            let obj = root.as_object_mut().expect("expected an object");
+            assert_eq!(obj.len(), 6);
+            assert_eq!(
+                obj.insert("a", KV3Value::Int(7)).and_then(|a| a.as_i64()),
+                Some(1)
+            );
+            let entries: Vec<_> = obj.iter().map(|(k, v)| (k.as_str(), v.as_i64())).collect();
+            assert_eq!(
+                entries,
+                [
+                    ("a", Some(7)),
+                    ("b", Some(2)),
+                    ("c", Some(4)),
+                    ("d", Some(6))
+                ]
+            );
+
+            let (_, mut root) = parse_kv3_with(input, &options).expect("expected to parse");
+            let obj = root.as_object_mut().expect("expected an object");
+            assert!(obj.remove("a").is_some());
+            assert_eq!(obj.keys().collect::<Vec<_>>(), ["b", "c", "d"]);
+        }
+    }
+
+    #[test]
+    fn kv3_value_paths() {
+        use crate::parse_kv3_with;
+
+        let input = r#"{
+            m_MassProperties = { m_vCenterOfMass = [ 0.0, 1.5, 2.0 ] }
+            m_Children = [
+                { m_hModel = resource:"models/a.vmdl" },
+                { m_hModel = resource:"models/b.vmdl" },
+            ]
+            "m_Key.x" = 1
+            "a/b~c" = 2
+        }"#;
+        let (_, mut root) = parse_kv3_with(input, &Default::default()).expect("expected to parse");
+
+        let center = root.get_path("m_MassProperties.m_vCenterOfMass[1]");
+        assert_eq!(center.and_then(KV3Value::as_f64), Some(1.5));
+        let model = root.get_path("m_Children[1].m_hModel");
+        assert_eq!(model.and_then(KV3Value::as_str), Some("models/b.vmdl"));
+        assert_eq!(
+            root.get_path("[\"m_Key.x\"]").and_then(KV3Value::as_i64),
+            Some(1)
+        );
+        assert!(root
+            .get_path("")
+            .is_some_and(|value| value.as_object().is_some()));
+        assert!(root.get_path("m_Children[2]").is_none());
+        assert!(root.get_path("m_Children.m_hModel").is_none());
+        assert!(root.get_path("m_Children[").is_none());
+
+        let model = root.pointer("/m_Children/0/m_hModel");
+        assert_eq!(model.and_then(KV3Value::as_str), Some("models/a.vmdl"));
+        assert_eq!(root.pointer("/a~1b~0c").and_then(KV3Value::as_i64), Some(2));
+        assert!(root.pointer("").is_some());
+        assert!(root.pointer("m_Children").is_none());
+        assert!(root.pointer("/m_Children/01").is_none());
+        assert!(root.pointer("/m_Children/2").is_none());
+
+        *root
+            .pointer_mut("/m_Children/0/m_hModel")
+            .expect("expected a value") = KV3Value::String("models/c.vmdl".to_string());
+        *root
+            .get_path_mut("m_MassProperties.m_vCenterOfMass[0]")
+            .expect("expected a value") = KV3Value::Double(0.5);
+        root.set_path("m_Children[1].m_hModel", KV3Value::Null)
+            .expect("expected to replace the value");
+        root.set_path("m_Children[1].m_nSkin", KV3Value::Int(3))
+            .expect("expected to add the key");
+        assert_eq!(
+            root["m_Children"][0]["m_hModel"].as_str(),
+            Some("models/c.vmdl")
+        );
+        assert_eq!(
+            root["m_MassProperties"]["m_vCenterOfMass"][0].as_f64(),
+            Some(0.5)
+        );
+        assert!(root["m_Children"][1]["m_hModel"].is_null());
+        assert_eq!(root["m_Children"][1]["m_nSkin"].as_i64(), Some(3));
+
+        for path in [
+            "",
+            "m_Children[2]",
+            "m_Missing.m_nSkin",
+            "m_Children.x",
+            "m_Children[0][0]",
+        ] {
+            assert!(matches!(
+                root.set_path(path, KV3Value::Null),
+                Err(Error::Path { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn kv3_select() {
+        use crate::{parse_kv3_with, select, DuplicateKeys, ParseOptions};
+
+        let input = r#"{
+            m_hMaterial = resource:"materials/root.vmat"
+            m_Shapes = [
+                { m_flRadius = 4.0 m_hMaterial = resource:"materials/a.vmat" },
+                { m_flRadius = 16 m_sName = "hull" },
+                { m_flRadius = 32.5 m_hMaterial = resource:"materials/c.vmat" m_sName = "head" },
+            ]
+            m_Children = { "a.b" = { m_hMaterial = "materials/d.vmat" } }
+            m_Numbers = [ 0, 1, 2, 3, 4, 5 ]
+        }"#;
+        let (_, root) = parse_kv3_with(input, &Default::default()).expect("expected to parse");
+
+        let paths = |query: &str| -> Vec<String> {
+            select(&root, query)
+                .expect("expected a valid query")
+                .into_iter()
+                .map(|selected| selected.path)
+                .collect()
+        };
+        let numbers = |query: &str| -> Vec<i64> {
+            select(&root, query)
+                .expect("expected a valid query")
+                .iter()
+                .filter_map(|selected| selected.value.as_i64())
+                .collect()
+        };
+
+        // Matches are in document order only with preserve_order
+        let materials = paths("$..m_hMaterial");
+        let expected = [
+            "m_hMaterial",
+            "m_Shapes[0].m_hMaterial",
+            "m_Shapes[2].m_hMaterial",
+            "m_Children[\"a.b\"].m_hMaterial",
+        ];
+        #[cfg(feature = "preserve_order")]
+        assert_eq!(materials, expected);
+        assert_eq!(materials.len(), expected.len());
+        assert!(expected
+            .iter()
+            .all(|path| materials.iter().any(|p| p == path)));
+        for selected in select(&root, "$..m_hMaterial").expect("expected a valid query") {
+            let value = root
+                .get_path(&selected.path)
+                .expect("expected the path to resolve");
+            assert!(std::ptr::eq(value, selected.value));
+        }
+        assert_eq!(paths("$"), [""]);
+        assert_eq!(
+            paths("$.m_Shapes[*].m_sName"),
+            ["m_Shapes[1].m_sName", "m_Shapes[2].m_sName"]
+        );
+        assert_eq!(paths("$['m_Children'].*"), ["m_Children[\"a.b\"]"]);
+        assert_eq!(paths("$..[\"a.b\"].m_hMaterial").len(), 1);
+        assert_eq!(paths("$.m_Shapes[-1].m_sName"), ["m_Shapes[2].m_sName"]);
+        assert!(paths("$.m_Shapes[3]").is_empty());
+        assert!(paths("$.m_Shapes[-4]").is_empty());
+        assert!(paths("$.m_Missing..m_sName").is_empty());
+
+        assert_eq!(numbers("$.m_Numbers[1:3]"), [1, 2]);
+        assert_eq!(numbers("$.m_Numbers[::2]"), [0, 2, 4]);
+        assert_eq!(numbers("$.m_Numbers[-2:]"), [4, 5]);
+        assert_eq!(numbers("$.m_Numbers[::-2]"), [5, 3, 1]);
+        assert_eq!(numbers("$.m_Numbers[4:1:-1]"), [4, 3, 2]);
+        assert_eq!(numbers("$.m_Numbers[:100]").len(), 6);
+        assert!(numbers("$.m_Numbers[::0]").is_empty());
+
+        assert_eq!(
+            paths("$.m_Shapes[?@.m_flRadius > 10]"),
+            ["m_Shapes[1]", "m_Shapes[2]"]
+        );
+        assert_eq!(
+            paths("$.m_Shapes[?(@.m_flRadius <= 16)]"),
+            ["m_Shapes[0]", "m_Shapes[1]"]
+        );
+        assert_eq!(paths("$.m_Shapes[?@.m_sName == 'head']"), ["m_Shapes[2]"]);
+        assert_eq!(paths("$.m_Shapes[?@.m_sName != \"head\"]").len(), 2);
+        assert_eq!(paths("$.m_Shapes[?@.m_hMaterial].m_flRadius").len(), 2);
+        assert_eq!(numbers("$.m_Numbers[?@ >= 4]"), [4, 5]);
+        assert_eq!(
+            paths("$..[?@.m_hMaterial == 'materials/d.vmat']"),
+            ["m_Children[\"a.b\"]"]
+        );
+
+        for query in ["m_Shapes", "$.", "$.m_Shapes[", "$.m_Shapes[?@.x >]", "$x"] {
+            assert!(
+                matches!(select(&root, query), Err(Error::Syntax { .. })),
+                "{}",
+                query
+            );
+        }
+        // Keys select the first of repeated values, other selectors all of them
+        let options = ParseOptions::new().duplicate_keys(DuplicateKeys::PreserveAll);
+        let (_, repeated) =
+            parse_kv3_with("{ a = 1 b = 2 a = 3 }", &options).expect("expected to parse");
+        let values = |query: &str| -> Vec<(String, Option<i64>)> {
+            select(&repeated, query)
+                .expect("expected a valid query")
+                .into_iter()
+                .map(|selected| (selected.path, selected.value.as_i64()))
+                .collect()
+        };
+        assert_eq!(values("$.a"), [("a".to_string(), Some(1))]);
+        assert_eq!(values("$[\"a\"]"), values("$.a"));
+        assert_eq!(values("$.*").len(), 3);
+        assert_eq!(values("$..*").len(), 3);
+        assert_eq!(values("$[?@ == 3]"), [("a".to_string(), Some(3))]);
+
+        assert_syntax_error(
+            select(&root, "$.m_Shapes[?@.m_flRadius > ]"),
+            1,
+            28,
+            "a number, string, `true`, `false` or `null`",
+        );
+    }
+
+    #[test]
+    fn kv3_macro() {
+        use crate::{kv3, parse_kv3_with};
+
+        let name = "m_sName";
+        let value = kv3!({
+            m_nFlags: 5,
+            m_nNegative: -1,
+            m_nHash: u64::MAX,
+            m_flScale: 0.5f32,
+            m_Names: ["a", "b",],
+            m_Empty: [],
+            m_EmptyObject: {},
+            blob: #[0xFF, 0x00],
+            m_EmptyBlob: #[],
+            "m_Data.m_nCount": 1 + 2,
+            (name): String::from("hull"),
+            m_Children: [
+                { m_bEnabled: true, m_Parent: null },
+                [false, 2.5],
+            ],
+        });
+
+        let input = r#"{
+            m_nFlags = 5
+            m_nNegative = -1
+            m_nHash = 18446744073709551615
+            m_flScale = 0.5
+            m_Names = [ "a", "b" ]
+            m_Empty = [  ]
+            m_EmptyObject = {}
+            blob = #[ FF 00 ]
+            m_EmptyBlob = #[]
+            "m_Data.m_nCount" = 3
+            m_sName = "hull"
+            m_Children = [ { m_bEnabled = true m_Parent = null }, [ false, 2.5 ] ]
+        }"#;
+        let (_, expected) = parse_kv3_with(input, &Default::default()).expect("expected to parse");
+
+        assert_eq!(value, expected);
+        #[cfg(feature = "preserve_order")]
+        assert_eq!(
+            to_kv3_string(&value).unwrap(),
+            to_kv3_string(&expected).unwrap()
+        );
+        assert_eq!(value["m_nHash"].as_u64(), Some(u64::MAX));
+        assert_eq!(value.as_object().map(|obj| obj.len()), Some(12));
+        assert!(matches!(kv3!([]), KV3Value::Array(items) if items.is_empty()));
+        assert!(matches!(kv3!(null), KV3Value::Null));
+        assert!(matches!(kv3!(-7), KV3Value::Int(-7)));
+        assert!(matches!(kv3!("x"), KV3Value::String(s) if s == "x"));
+    }
+
+    #[test]
+    fn kv3_macro_many_entries() {
+        use crate::kv3;
+
+        // Each entry or item is one level of macro recursion
+        let object = kv3!({
+            k0: 0, k1: 1, k2: 2, k3: 3, k4: 4, k5: 5, k6: 6, k7: 7, k8: 8, k9: 9,
+            k10: 10, k11: 11, k12: 12, k13: 13, k14: 14, k15: 15, k16: 16, k17: 17, k18: 18, k19: 19,
+            k20: 20, k21: 21, k22: 22, k23: 23, k24: 24, k25: 25, k26: 26, k27: 27, k28: 28, k29: 29,
+            k30: 30, k31: 31, k32: 32, k33: 33, k34: 34, k35: 35, k36: 36, k37: 37, k38: 38, k39: 39,
+            k40: 40, k41: 41, k42: 42, k43: 43, k44: 44, k45: 45, k46: 46, k47: 47, k48: 48, k49: 49,
+            k50: 50, k51: 51, k52: 52, k53: 53, k54: 54, k55: 55, k56: 56, k57: 57, k58: 58, k59: 59,
+            k60: 60, k61: 61, k62: 62, k63: 63, k64: 64, k65: 65, k66: 66, k67: 67, k68: 68, k69: 69,
+            k70: 70, k71: 71, k72: 72, k73: 73, k74: 74, k75: 75, k76: 76, k77: 77, k78: 78, k79: 79,
+            k80: 80, k81: 81, k82: 82, k83: 83, k84: 84, k85: 85, k86: 86, k87: 87, k88: 88, k89: 89,
+            k90: 90, k91: 91, k92: 92, k93: 93, k94: 94, k95: 95, k96: 96, k97: 97, k98: 98, k99: 99,
+            k100: 100, k101: 101, k102: 102, k103: 103, k104: 104, k105: 105, k106: 106, k107: 107, k108: 108, k109: 109,
+            k110: 110, k111: 111, k112: 112, k113: 113, k114: 114, k115: 115, k116: 116, k117: 117, k118: 118, k119: 119,
+        });
+        assert_eq!(object.as_object().map(|obj| obj.len()), Some(120));
+        assert!((0..120).all(|i| object[format!("k{}", i).as_str()].as_i64() == Some(i)));
+
+        let array = kv3!([
+            -0, -1, -2, -3, -4, -5, -6, -7, -8, -9, -10, -11, -12, -13, -14, -15, -16, -17, -18,
+            -19, -20, -21, -22, -23, -24, -25, -26, -27, -28, -29, -30, -31, -32, -33, -34, -35,
+            -36, -37, -38, -39, -40, -41, -42, -43, -44, -45, -46, -47, -48, -49, -50, -51, -52,
+            -53, -54, -55, -56, -57, -58, -59, -60, -61, -62, -63, -64, -65, -66, -67, -68, -69,
+            -70, -71, -72, -73, -74, -75, -76, -77, -78, -79, -80, -81, -82, -83, -84, -85, -86,
+            -87, -88, -89, -90, -91, -92, -93, -94, -95, -96, -97, -98, -99, -100, -101, -102,
+            -103, -104, -105, -106, -107, -108, -109, -110, -111, -112, -113, -114, -115, -116,
+            -117, -118, -119,
+        ]);
+        let items = array.as_array().expect("expected an array");
+        assert_eq!(items.len(), 120);
+        assert!((0..120).all(|i| items[i as usize].as_i64() == Some(-i)));
+    }
+
+    #[test]
+    fn kv3_value_equality() {
+        use std::collections::HashMap;
+
+        use crate::{kv3, parse_kv3_with, DuplicateKeys, KV3Object, ParseOptions};
+
+        let input = r#"{
+            m_nCount = 3
+            m_flScale = 1.50
+            m_flNaN = nan
+            m_hModel = resource:"models/foo.vmdl"
+            m_Data = #[ 01 02 ]
+            m_Items = [ { a = 1 b = 2 } ]
+        }"#;
+        let (_, parsed) = parse_kv3_with(input, &Default::default()).expect("expected to parse");
+        let (_, kept) = parse_kv3_with(input, &ParseOptions::new().keep_number_text(true))
+            .expect("expected to parse");
+
+        // NaN equals itself and kept number text does not matter
+        assert_eq!(parsed, parsed.clone());
+        assert_eq!(parsed, kept);
+        assert_eq!(kept["m_flScale"], KV3Value::Double(1.5));
+
+        let mut changed = parsed.clone();
+        changed
+            .set_path("m_Items[0].b", kv3!(3))
+            .expect("expected to set");
+        assert_ne!(parsed, changed);
+        assert_eq!(parsed["m_Items"][0]["b"], kv3!(2));
+
+        // Objects compare regardless of key order
+        assert_eq!(kv3!({ a: 1, b: 2 }), kv3!({ b: 2, a: 1 }));
+        assert_ne!(kv3!({ a: 1 }), kv3!({ a: 1, b: 2 }));
+
+        // So do repeated keys, but their values keep their order
+        let all = ParseOptions::new().duplicate_keys(DuplicateKeys::PreserveAll);
+        let parse = |input| parse_kv3_with(input, &all).expect("expected to parse").1;
+        assert_eq!(
+            parse("{ a = 1 b = 2 a = 3 c = 4 b = 5 }"),
+            parse("{ c = 4 b = 2 b = 5 a = 1 a = 3 }")
+        );
+        assert_ne!(
+            parse("{ a = 1 b = 2 a = 3 }"),
+            parse("{ a = 3 b = 2 a = 1 }")
+        );
+        assert_ne!(parse("{ a = 1 a = 1 }"), parse("{ a = 1 }"));
+
+        assert_eq!(KV3Value::Int(5), KV3Value::UInt(5));
+        assert_ne!(KV3Value::Int(-1), KV3Value::UInt(u64::MAX));
+        assert_ne!(KV3Value::Int(1), KV3Value::Double(1.0));
+        assert_eq!(KV3Value::Double(0.0), KV3Value::Double(-0.0));
+        assert_ne!(KV3Value::Double(f64::NAN), KV3Value::Double(f64::INFINITY));
+        assert_ne!(
+            parsed["m_hModel"],
+            KV3Value::String("models/foo.vmdl".to_string())
+        );
+
+        assert_eq!(KV3Value::default(), KV3Value::Null);
+        assert_eq!(KV3Object::default().len(), 0);
+        assert_eq!(KV3Value::from(vec![1u8, 2]), KV3Value::HexArray(vec![1, 2]));
+        assert_eq!(
+            KV3Value::from(vec![1, 2]),
+            KV3Value::Array(vec![KV3Value::Int(1), KV3Value::Int(2)])
+        );
+        assert_eq!(KV3Value::from(vec!["a"]), kv3!(["a"]));
+        assert_eq!(KV3Value::from(u64::MAX), KV3Value::UInt(u64::MAX));
+        assert_eq!(KV3Value::from(u64::MAX)["x"], KV3Value::Null);
+        assert_eq!(
+            KV3Value::from(HashMap::from([("m_nCount", 3), ("m_nOther", 4)])),
+            kv3!({ m_nOther: 4, m_nCount: 3 })
+        );
+        assert_eq!(
+            KV3Value::from(HashMap::from([(String::from("m_Data"), vec![1u8, 2])])),
+            kv3!({ m_Data: #[1, 2] })
+        );
     }
 }
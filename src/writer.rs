@@ -0,0 +1,305 @@
+use std::{borrow::Cow, io};
+
+use crate::{is_key_char, parse_number_text, Error, KV3Header, KV3Object, KV3Value};
+
+/// Number of bytes written per line inside a `#[ ... ]` blob.
+const HEX_BYTES_PER_LINE: usize = 32;
+
+/// Arrays of plain scalars up to this length are written on a single line.
+const INLINE_ARRAY_MAX_LEN: usize = 4;
+
+/// Serializes a [`KV3Value`] into KV3 text, including the default
+/// `text`/`generic` header.
+///
+/// # Example
+///
+/// ```rust
+/// use kv3::{parse_kv3_document, to_kv3_string};
+///
+/// let input = r#"<!-- kv3 encoding:text:version{e21c7f3c-8a33-41c5-9977-a76d3a32aa0d} format:generic:version{7412167c-06e9-4698-aff2-e63eb59037e7} -->
+/// { m_nFlags = 5 }
+/// "#;
+///
+/// let (_, root) = parse_kv3_document(input).unwrap();
+/// let text = to_kv3_string(&root).unwrap();
+/// assert!(text.contains("m_nFlags = 5"));
+/// ```
+///
+/// # Errors
+///
+/// Fails if `value` is not an object, as the root of a KV3 document has to
+/// be one.
+pub fn to_kv3_string(value: &KV3Value) -> Result<String, Error> {
+    let mut buffer = Vec::new();
+    write_kv3(&mut buffer, value)?;
+    // The writer only ever emits UTF-8 input strings and ASCII syntax.
+    Ok(String::from_utf8(buffer).expect("KV3 writer produced invalid UTF-8"))
+}
+
+/// Writes a [`KV3Value`] as KV3 text, including the default `text`/`generic`
+/// header. Fails with [`io::ErrorKind::InvalidInput`] if `value` is not an
+/// object.
+pub fn write_kv3<W: io::Write>(writer: W, value: &KV3Value) -> io::Result<()> {
+    write_kv3_document(writer, &KV3Header::default(), value)
+}
+
+/// Writes a [`KV3Value`] as KV3 text using the given header, e.g. one
+/// obtained from [`parse_kv3_document`](crate::parse_kv3_document). Fails
+/// with [`io::ErrorKind::InvalidInput`] if `value` is not an object.
+pub fn write_kv3_document<W: io::Write>(
+    writer: W,
+    header: &KV3Header,
+    value: &KV3Value,
+) -> io::Result<()> {
+    if !matches!(value, KV3Value::Object(_)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the root of a KV3 document must be an object",
+        ));
+    }
+
+    let mut writer = KV3Writer::new(writer, String::new());
+    writeln!(writer.writer, "{}", header)?;
+    writer.write_value(value)?;
+    writeln!(writer.writer)
+}
+
+/// Writes `value` without a header, for splicing into text whose current
+/// line is indented by `margin`.
+pub(crate) fn value_to_string(value: &KV3Value, margin: &str) -> String {
+    let mut writer = KV3Writer::new(Vec::new(), margin.to_string());
+    writer
+        .write_value(value)
+        .expect("writing to a Vec<u8> cannot fail");
+    String::from_utf8(writer.writer).expect("KV3 writer produced invalid UTF-8")
+}
+
+/// Writes a `key = value` entry the way [`value_to_string`] writes values.
+pub(crate) fn entry_to_string(key: &str, value: &KV3Value, margin: &str) -> String {
+    let mut writer = KV3Writer::new(Vec::new(), margin.to_string());
+    writer
+        .write_entry(key, value)
+        .expect("writing to a Vec<u8> cannot fail");
+    String::from_utf8(writer.writer).expect("KV3 writer produced invalid UTF-8")
+}
+
+struct KV3Writer<W> {
+    writer: W,
+    indent: usize,
+    /// Written before the indentation of every line after the first.
+    margin: String,
+}
+
+impl<W: io::Write> KV3Writer<W> {
+    fn new(writer: W, margin: String) -> Self {
+        KV3Writer {
+            writer,
+            indent: 0,
+            margin,
+        }
+    }
+
+    fn write_indent(&mut self) -> io::Result<()> {
+        self.writer.write_all(self.margin.as_bytes())?;
+        for _ in 0..self.indent {
+            self.writer.write_all(b"\t")?;
+        }
+        Ok(())
+    }
+
+    fn write_value(&mut self, value: &KV3Value) -> io::Result<()> {
+        match value {
+            KV3Value::Bool(b) => write!(self.writer, "{}", b),
+            KV3Value::Int(i) => write!(self.writer, "{}", i),
+            KV3Value::UInt(u) => write!(self.writer, "{}", u),
+            KV3Value::Double(d) if d.is_nan() => write!(self.writer, "nan"),
+            KV3Value::Double(d) if d.is_infinite() => {
+                write!(self.writer, "{}", if *d > 0.0 { "inf" } else { "-inf" })
+            }
+            // `{:?}` always keeps a `.` or exponent, so the value reads back as a double
+            KV3Value::Double(d) => write!(self.writer, "{:?}", d),
+            KV3Value::String(s) => self.write_string(s),
+            KV3Value::Array(items) => self.write_array(items),
+            KV3Value::HexArray(bytes) => self.write_hex_array(bytes),
+            KV3Value::Object(obj) => self.write_object(obj),
+            KV3Value::Null => write!(self.writer, "null"),
+            KV3Value::Flagged { flags, value } => {
+                // Bits without a name, e.g. from binary KV3, have no text form
+                if !flags.named().is_empty() {
+                    write!(self.writer, "{}:", flags)?;
+                }
+                self.write_value(value)
+            }
+            KV3Value::Number { value, text } if number_text_matches(text, value) => {
+                write!(self.writer, "{}", text)
+            }
+            KV3Value::Number { value, .. } => self.write_value(value),
+        }
+    }
+
+    fn write_string(&mut self, s: &str) -> io::Result<()> {
+        if is_multiline(s) {
+            // The opening and closing newlines are not part of the string
+            write!(self.writer, "\"\"\"\n{}\n\"\"\"", s)
+        } else {
+            write!(self.writer, "\"{}\"", escape(s))
+        }
+    }
+
+    fn write_key(&mut self, key: &str) -> io::Result<()> {
+        if key.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "KV3 keys cannot be empty",
+            ));
+        }
+        if key.chars().all(is_key_char) {
+            write!(self.writer, "{}", key)
+        } else {
+            write!(self.writer, "\"{}\"", escape(key))
+        }
+    }
+
+    fn write_object(&mut self, obj: &KV3Object) -> io::Result<()> {
+        if obj.fields.is_empty() {
+            return write!(self.writer, "{{}}");
+        }
+
+        writeln!(self.writer, "{{")?;
+        self.indent += 1;
+        for (key, value) in obj.iter() {
+            self.write_indent()?;
+            self.write_entry(key, value)?;
+            writeln!(self.writer)?;
+        }
+        self.indent -= 1;
+        self.write_indent()?;
+        write!(self.writer, "}}")
+    }
+
+    fn write_entry(&mut self, key: &str, value: &KV3Value) -> io::Result<()> {
+        self.write_key(key)?;
+        write!(self.writer, " = ")?;
+        if is_block(value) {
+            writeln!(self.writer)?;
+            self.write_indent()?;
+        }
+        self.write_value(value)
+    }
+
+    fn write_array(&mut self, items: &[KV3Value]) -> io::Result<()> {
+        if items.is_empty() {
+            return write!(self.writer, "[  ]");
+        }
+
+        if is_inline_array(items) {
+            write!(self.writer, "[ ")?;
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    write!(self.writer, ", ")?;
+                }
+                self.write_value(item)?;
+            }
+            return write!(self.writer, " ]");
+        }
+
+        writeln!(self.writer, "[")?;
+        self.indent += 1;
+        for item in items {
+            self.write_indent()?;
+            self.write_value(item)?;
+            writeln!(self.writer, ",")?;
+        }
+        self.indent -= 1;
+        self.write_indent()?;
+        write!(self.writer, "]")
+    }
+
+    fn write_hex_array(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            return write!(self.writer, "#[]");
+        }
+
+        writeln!(self.writer, "#[")?;
+        self.indent += 1;
+        for line in bytes.chunks(HEX_BYTES_PER_LINE) {
+            self.write_indent()?;
+            for (i, byte) in line.iter().enumerate() {
+                if i > 0 {
+                    write!(self.writer, " ")?;
+                }
+                write!(self.writer, "{:02X}", byte)?;
+            }
+            writeln!(self.writer)?;
+        }
+        self.indent -= 1;
+        self.write_indent()?;
+        write!(self.writer, "]")
+    }
+}
+
+/// Values that Valve's tools place on their own line after `key = `.
+fn is_block(value: &KV3Value) -> bool {
+    match value {
+        KV3Value::Object(obj) => !obj.fields.is_empty(),
+        KV3Value::Array(items) => !(items.is_empty() || is_inline_array(items)),
+        KV3Value::HexArray(bytes) => !bytes.is_empty(),
+        _ => false,
+    }
+}
+
+fn is_inline_array(items: &[KV3Value]) -> bool {
+    items.len() <= INLINE_ARRAY_MAX_LEN && items.iter().all(is_inline_scalar)
+}
+
+fn is_inline_scalar(value: &KV3Value) -> bool {
+    match value {
+        KV3Value::Bool(_)
+        | KV3Value::Int(_)
+        | KV3Value::UInt(_)
+        | KV3Value::Double(_)
+        | KV3Value::Null => true,
+        KV3Value::String(s) => !is_multiline(s),
+        KV3Value::Flagged { value, .. } | KV3Value::Number { value, .. } => is_inline_scalar(value),
+        _ => false,
+    }
+}
+
+/// Strings with line breaks are written as `"""` strings, unless they
+/// contain something a `"""` string cannot hold.
+fn is_multiline(s: &str) -> bool {
+    // A trailing `\r` would be taken for part of the closing line break
+    s.contains('\n') && !s.contains("\"\"\"") && !s.ends_with('\r')
+}
+
+/// Escapes a string for a single-line `"..."` string or a quoted key.
+pub(crate) fn escape(s: &str) -> Cow<'_, str> {
+    if !s.contains(['"', '\\', '\n', '\t', '\r']) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut escaped = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Whether `text` still reads back as `value`, so it can be written instead.
+fn number_text_matches(text: &str, value: &KV3Value) -> bool {
+    match (parse_number_text(text), value) {
+        (Some(KV3Value::Int(a)), KV3Value::Int(b)) => a == *b,
+        (Some(KV3Value::UInt(a)), KV3Value::UInt(b)) => a == *b,
+        (Some(KV3Value::Double(a)), KV3Value::Double(b)) => {
+            a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+        }
+        _ => false,
+    }
+}